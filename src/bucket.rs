@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use crate::error::{IoContext, ShuffleError};
+
+/// Bytes of framing in front of every temp record: key (u64), source (u32),
+/// ordinal (u64), copy (u32) and length (u32), all little-endian.
+pub(crate) const HEADER_LEN: usize = 28;
+
+/// A record in a temp bucket, tagged with its sort key and where it came from.
+///
+/// Phase 1 writes buckets from many tasks at once, so the order records land
+/// in a bucket depends on scheduling. Phase 2 sorts by `key` (ties broken by
+/// `(source, ordinal, copy)`), which is what actually shuffles the records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TempRecord {
+    /// Pseudo-random sort key derived from the seed and the record's origin
+    pub(crate) key: u64,
+    /// Index of the input file in sorted input order
+    pub(crate) source: u32,
+    /// Position of the record within its input file, counting blank records
+    pub(crate) ordinal: u64,
+    /// Which repetition of the record this is, when mixing repeats it
+    pub(crate) copy: u32,
+    pub(crate) line: String,
+}
+
+impl TempRecord {
+    /// Size of the record once framed in a temp file.
+    pub(crate) fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.line.len()
+    }
+
+    pub(crate) async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&self.header()?).await?;
+        writer.write_all(self.line.as_bytes()).await
+    }
+
+    /// Append the framed record to an in-memory buffer.
+    pub(crate) fn encode_into(&self, buffer: &mut Vec<u8>) -> Result<(), io::Error> {
+        buffer.extend_from_slice(&self.header()?);
+        buffer.extend_from_slice(self.line.as_bytes());
+        Ok(())
+    }
+
+    fn header(&self) -> Result<[u8; HEADER_LEN], io::Error> {
+        let len = u32::try_from(self.line.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "record larger than 4 GiB")
+        })?;
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(&self.key.to_le_bytes());
+        header[8..12].copy_from_slice(&self.source.to_le_bytes());
+        header[12..20].copy_from_slice(&self.ordinal.to_le_bytes());
+        header[20..24].copy_from_slice(&self.copy.to_le_bytes());
+        header[24..].copy_from_slice(&len.to_le_bytes());
+        Ok(header)
+    }
+}
+
+/// Reads `TempRecord`s back from a bucket file.
+pub(crate) struct TempRecordReader<R> {
+    inner: R,
+}
+
+impl<R: AsyncRead + Unpin> TempRecordReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Read the next record, or `None` at a clean end of file. A record cut
+    /// off part way is an `UnexpectedEof` error.
+    pub(crate) async fn next_record(&mut self) -> Result<Option<TempRecord>, io::Error> {
+        let mut header = [0u8; HEADER_LEN];
+        let first = self.inner.read(&mut header).await?;
+        if first == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut header[first..]).await?;
+
+        let key = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let source = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let ordinal = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let copy = u32::from_le_bytes(header[20..24].try_into().unwrap());
+        let len = u32::from_le_bytes(header[24..].try_into().unwrap()) as usize;
+
+        let mut bytes = vec![0u8; len];
+        self.inner.read_exact(&mut bytes).await?;
+        let line = String::from_utf8(bytes).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "temp record is not valid UTF-8")
+        })?;
+
+        Ok(Some(TempRecord { key, source, ordinal, copy, line }))
+    }
+}
+
+/// Scatters records into bucket files through bounded per-bucket buffers.
+///
+/// Each bucket's buffer is allocated once at exactly `buffer_limit` bytes
+/// and written out before it would grow past that; records too large for a
+/// buffer are written on their own. Files stay open between writes, but at
+/// most `max_open` at a time; when another is needed the least recently
+/// written one is closed. Bucket files must already exist and are appended to.
+pub(crate) struct BucketWriters {
+    paths: Vec<PathBuf>,
+    buffers: Vec<Vec<u8>>,
+    /// Bytes and records in each bucket file, counting buffered ones
+    lengths: Vec<u64>,
+    records: Vec<u64>,
+    buffer_limit: usize,
+    max_open: usize,
+    open: HashMap<usize, (File, u64)>, // bucket -> (file, last use)
+    lru: BTreeMap<u64, usize>,         // last use -> bucket
+    clock: u64,
+}
+
+impl BucketWriters {
+    pub(crate) fn new(paths: Vec<PathBuf>, buffer_limit: usize, max_open: usize) -> Self {
+        Self {
+            buffers: vec![Vec::new(); paths.len()],
+            lengths: vec![0; paths.len()],
+            records: vec![0; paths.len()],
+            paths,
+            buffer_limit,
+            max_open: max_open.max(1),
+            open: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Continue from bucket files that already hold these many bytes and records.
+    pub(crate) fn restore(&mut self, lengths: Vec<u64>, records: Vec<u64>) {
+        self.lengths = lengths;
+        self.records = records;
+    }
+
+    /// Buffer a record for `bucket`, writing the buffer out first if the
+    /// record would not fit.
+    pub(crate) async fn push(&mut self, bucket: usize, record: &TempRecord) -> Result<(), ShuffleError> {
+        let len = record.encoded_len();
+        if self.buffers[bucket].len() + len > self.buffer_limit && !self.buffers[bucket].is_empty() {
+            self.write_out(bucket).await?;
+        }
+
+        if len > self.buffer_limit {
+            let mut bytes = Vec::with_capacity(len);
+            record.encode_into(&mut bytes).at(&self.paths[bucket])?;
+            self.write_bytes(bucket, &bytes).await?;
+        } else {
+            let buffer = &mut self.buffers[bucket];
+            if buffer.capacity() == 0 {
+                buffer.reserve_exact(self.buffer_limit);
+            }
+            record.encode_into(buffer).at(&self.paths[bucket])?;
+        }
+        self.lengths[bucket] += len as u64;
+        self.records[bucket] += 1;
+        Ok(())
+    }
+
+    /// Write out every buffer so the bucket files hold every record pushed
+    /// so far, and return their sizes and record counts.
+    pub(crate) async fn checkpoint(&mut self) -> Result<(Vec<u64>, Vec<u64>), ShuffleError> {
+        for bucket in 0..self.buffers.len() {
+            if !self.buffers[bucket].is_empty() {
+                self.write_out(bucket).await?;
+            }
+        }
+        for (&bucket, (file, _)) in self.open.iter_mut() {
+            file.flush().await.at(&self.paths[bucket])?;
+        }
+        Ok((self.lengths.clone(), self.records.clone()))
+    }
+
+    async fn write_out(&mut self, bucket: usize) -> Result<(), ShuffleError> {
+        // Keep the allocation for the next records
+        let mut buffer = std::mem::take(&mut self.buffers[bucket]);
+        self.write_bytes(bucket, &buffer).await?;
+        buffer.clear();
+        self.buffers[bucket] = buffer;
+        Ok(())
+    }
+
+    async fn write_bytes(&mut self, bucket: usize, bytes: &[u8]) -> Result<(), ShuffleError> {
+        self.clock += 1;
+        if let Some((_, last_used)) = self.open.get_mut(&bucket) {
+            self.lru.remove(last_used);
+            *last_used = self.clock;
+        } else {
+            if self.open.len() >= self.max_open {
+                let (_, evicted) = self.lru.pop_first().unwrap();
+                let (mut file, _) = self.open.remove(&evicted).unwrap();
+                file.flush().await.at(&self.paths[evicted])?;
+            }
+            let file = OpenOptions::new().append(true).open(&self.paths[bucket]).await.at(&self.paths[bucket])?;
+            self.open.insert(bucket, (file, self.clock));
+        }
+        self.lru.insert(self.clock, bucket);
+
+        let (file, _) = self.open.get_mut(&bucket).unwrap();
+        file.write_all(bytes).await.at(&self.paths[bucket])
+    }
+}
+
+/// How many bucket files phase 1 may keep open at once: half the process's
+/// soft file descriptor limit, and never fewer than `floor`. The limit is
+/// only read; raising it is left to the embedding program (the CLI does).
+pub(crate) fn max_open_buckets(floor: usize) -> usize {
+    (file_descriptor_limit() / 2).max(floor)
+}
+
+#[cfg(unix)]
+fn file_descriptor_limit() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes the struct passed in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return 0;
+    }
+    // An unlimited limit can't actually be used; keep to something sane
+    limit.rlim_cur.min(1 << 20) as usize
+}
+
+#[cfg(not(unix))]
+fn file_descriptor_limit() -> usize {
+    0
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`,
+/// or `None` where that can't be determined.
+#[cfg(unix)]
+pub(crate) fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs only writes to the struct passed in; zeroed is a valid value for it
+    unsafe {
+        let mut stats: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stats) != 0 {
+            return None;
+        }
+        #[allow(clippy::unnecessary_cast)] // field types differ between platforms
+        Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Identifies the filesystem holding `path`, so directories that share one
+/// can be checked together.
+#[cfg(unix)]
+pub(crate) fn filesystem_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn filesystem_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Memory needed to sort a bucket of `records` records stored in
+/// `file_bytes` bytes: the decoded records plus the lines handed on after
+/// sorting, which are briefly alive at the same time.
+pub(crate) fn in_memory_size(file_bytes: u64, records: u64) -> u64 {
+    let payload = file_bytes.saturating_sub(records * HEADER_LEN as u64);
+    payload + records * (size_of::<TempRecord>() + size_of::<String>()) as u64
+}
+
+/// Open a bucket file for reading.
+pub(crate) async fn open_bucket(path: &Path) -> Result<TempRecordReader<BufReader<File>>, io::Error> {
+    Ok(TempRecordReader::new(BufReader::new(File::open(path).await?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: u32, ordinal: u64, line: &str) -> TempRecord {
+        TempRecord { key: ordinal.wrapping_mul(31), source, ordinal, copy: 0, line: line.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let records = vec![record(0, 0, "a\nb"), record(7, u64::MAX, ""), record(3, 12, "{\"x\": 1}")];
+        let mut bytes = Vec::new();
+        for r in &records {
+            r.write_to(&mut bytes).await.unwrap();
+        }
+        assert_eq!(bytes.len(), records.iter().map(TempRecord::encoded_len).sum::<usize>());
+
+        let mut reader = TempRecordReader::new(&bytes[..]);
+        let mut read = Vec::new();
+        while let Some(r) = reader.next_record().await.unwrap() {
+            read.push(r);
+        }
+        assert_eq!(read, records);
+    }
+
+    #[tokio::test]
+    async fn test_bucket_writers_with_few_descriptors() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let paths: Vec<PathBuf> = (0..5).map(|i| temp_dir.path().join(format!("bucket_{}", i))).collect();
+        for path in &paths {
+            std::fs::File::create(path).unwrap();
+        }
+
+        // Tiny buffers and two descriptors force constant eviction and reopening
+        let mut writers = BucketWriters::new(paths.clone(), 40, 2);
+        let mut expected = vec![Vec::new(); paths.len()];
+        for i in 0..200u64 {
+            let bucket = (i * 7 % 5) as usize;
+            // Every 50th record is too big for the buffer and bypasses it
+            let r = record(bucket as u32, i, &format!("record {}{}", i, "!".repeat(usize::from(i % 50 == 0) * 60)));
+            writers.push(bucket, &r).await.unwrap();
+            expected[bucket].push(r);
+        }
+        let (lengths, counts) = writers.checkpoint().await.unwrap();
+        drop(writers);
+        for (path, length) in paths.iter().zip(lengths) {
+            assert_eq!(std::fs::metadata(path).unwrap().len(), length);
+        }
+        assert_eq!(counts, expected.iter().map(|records| records.len() as u64).collect::<Vec<_>>());
+
+        for (path, expected) in paths.iter().zip(expected) {
+            let mut reader = open_bucket(path).await.unwrap();
+            let mut read = Vec::new();
+            while let Some(r) = reader.next_record().await.unwrap() {
+                read.push(r);
+            }
+            assert_eq!(read, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_record_is_an_error() {
+        let mut bytes = Vec::new();
+        record(1, 2, "hello").write_to(&mut bytes).await.unwrap();
+        bytes.pop();
+
+        let err = TempRecordReader::new(&bytes[..]).next_record().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
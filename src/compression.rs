@@ -0,0 +1,391 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, Lz4Decoder, XzDecoder, ZstdDecoder};
+use async_compression::tokio::write::{BzEncoder, GzipEncoder, Lz4Encoder, XzEncoder, ZstdEncoder};
+use async_compression::zstd::DParameter;
+use async_compression::Level;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, BufReader, BufWriter};
+use crate::error::ShuffleError;
+
+/// Largest zstd window we are willing to decode. Files written with
+/// `--long=31` need this; the zstd default refuses anything above 2^27.
+#[cfg(target_pointer_width = "64")]
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+#[cfg(not(target_pointer_width = "64"))]
+const ZSTD_WINDOW_LOG_MAX: u32 = 30;
+
+/// Compression codec of an input file or of the output shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    Lz4,
+}
+
+impl Compression {
+    /// Every supported codec, uncompressed first.
+    pub const ALL: [Compression; 6] = [
+        Compression::None,
+        Compression::Gzip,
+        Compression::Zstd,
+        Compression::Xz,
+        Compression::Bzip2,
+        Compression::Lz4,
+    ];
+
+    /// File extensions recognised for this codec, canonical one first.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Compression::None => &[],
+            Compression::Gzip => &["gz"],
+            Compression::Zstd => &["zst", "zstd"],
+            Compression::Xz => &["xz"],
+            Compression::Bzip2 => &["bz2"],
+            Compression::Lz4 => &["lz4"],
+        }
+    }
+
+    /// Extension appended to output files written with this codec.
+    pub fn extension(self) -> Option<&'static str> {
+        self.extensions().first().copied()
+    }
+
+    /// Detect the codec of a file from its final extension.
+    pub fn from_path(path: &Path) -> Self {
+        let ext = match path.extension().and_then(|s| s.to_str()) {
+            Some(ext) => ext,
+            None => return Compression::None,
+        };
+        Compression::ALL
+            .into_iter()
+            .find(|codec| codec.extensions().contains(&ext))
+            .unwrap_or(Compression::None)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bz2",
+            Compression::Lz4 => "lz4",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ShuffleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            "bz2" | "bzip2" => Ok(Compression::Bzip2),
+            "lz4" => Ok(Compression::Lz4),
+            other => Err(ShuffleError::InvalidConfig(
+                format!("unknown compression '{}' (expected none, gzip, zstd, xz, bz2 or lz4)", other),
+            )),
+        }
+    }
+}
+
+impl From<Compression> for String {
+    fn from(compression: Compression) -> Self {
+        compression.to_string()
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = ShuffleError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+/// Open an input file, transparently decompressing it based on its extension.
+///
+/// Concatenated gzip members, multi-frame zstd files and other multi-stream
+/// inputs are read to the end.
+pub(crate) async fn open_input(path: &Path) -> Result<Box<dyn AsyncBufRead + Unpin + Send>, io::Error> {
+    let file = File::open(path).await?;
+    Ok(decoder(BufReader::new(file), Compression::from_path(path)))
+}
+
+/// Wrap a buffered reader in a decoder for `codec`.
+pub(crate) fn decoder<'a, R>(reader: R, codec: Compression) -> Box<dyn AsyncBufRead + Unpin + Send + 'a>
+where
+    R: AsyncBufRead + Unpin + Send + 'a,
+{
+    match codec {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::with_params(
+                reader,
+                &[DParameter::window_log_max(ZSTD_WINDOW_LOG_MAX)],
+            );
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+        Compression::Xz => {
+            let mut decoder = XzDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+        Compression::Bzip2 => {
+            let mut decoder = BzDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+        Compression::Lz4 => {
+            let mut decoder = Lz4Decoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+    }
+}
+
+/// Estimate the decompressed size of an input file without reading all of it.
+///
+/// Every codec gets a sampling estimate: the first `SAMPLE_SIZE` compressed
+/// bytes are decoded and their ratio extrapolated to the whole file (exact
+/// when the file fits in the sample). Gzip's ISIZE trailer and zstd's frame
+/// content size are exact for single-stream files, so they are preferred when
+/// they roughly agree with the sample; multi-member gzip and multi-frame zstd
+/// files only describe their last member / first frame and fall back to
+/// sampling.
+pub(crate) async fn estimate_uncompressed_size(path: &Path) -> Result<u64, io::Error> {
+    const SAMPLE_SIZE: u64 = 1024 * 1024;
+
+    let codec = Compression::from_path(path);
+    let compressed_size = tokio::fs::metadata(path).await?.len();
+    if codec == Compression::None || compressed_size == 0 {
+        return Ok(compressed_size);
+    }
+
+    let mut file = File::open(path).await?;
+    let mut sample = Vec::new();
+    (&mut file).take(SAMPLE_SIZE).read_to_end(&mut sample).await?;
+
+    // Decode as much of the sample as possible; truncation ends in an error
+    let mut reader = decoder(&sample[..], codec);
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut decoded = 0u64;
+    while let Ok(n) = reader.read(&mut chunk).await {
+        if n == 0 {
+            break;
+        }
+        decoded += n as u64;
+    }
+
+    if sample.len() as u64 == compressed_size {
+        return Ok(decoded);
+    }
+    if decoded == 0 {
+        // Nothing decodable in the sample, so the compressed size is all we know
+        return Ok(compressed_size);
+    }
+    let sampled = (decoded as f64 / sample.len() as f64 * compressed_size as f64) as u64;
+
+    let declared = match codec {
+        Compression::Gzip if compressed_size < 1 << 32 => {
+            let mut trailer = [0u8; 4];
+            file.seek(io::SeekFrom::End(-4)).await?;
+            file.read_exact(&mut trailer).await?;
+            Some(u32::from_le_bytes(trailer) as u64)
+        }
+        Compression::Zstd => zstd_frame_content_size(&sample),
+        _ => None,
+    };
+
+    Ok(match declared {
+        Some(size) if size / 2 <= sampled && sampled / 2 <= size => size,
+        _ => sampled,
+    })
+}
+
+/// Read the frame content size from the header of the first zstd frame.
+fn zstd_frame_content_size(bytes: &[u8]) -> Option<u64> {
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    if bytes.get(..4)? != ZSTD_MAGIC {
+        return None;
+    }
+    let descriptor = *bytes.get(4)?;
+    let fcs_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let dict_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+
+    let fcs_size = match fcs_flag {
+        0 if single_segment => 1,
+        0 => return None,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let start = 5 + usize::from(!single_segment) + dict_id_size;
+    let field = bytes.get(start..start + fcs_size)?;
+
+    let mut value = [0u8; 8];
+    value[..fcs_size].copy_from_slice(field);
+    let size = u64::from_le_bytes(value);
+    // The two byte form is stored with an offset of 256
+    Some(if fcs_size == 2 { size + 256 } else { size })
+}
+
+/// Wrap an output sink in a buffered encoder for `codec`.
+///
+/// `level` uses each codec's native scale and is clamped to its valid range;
+/// `None` picks the codec default. Callers must `shutdown()` the writer so
+/// compressed streams are finished.
+pub(crate) fn output_writer<'a, W>(inner: W, codec: Compression, level: Option<i32>) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+where
+    W: AsyncWrite + Unpin + Send + 'a,
+{
+    let level = level.map_or(Level::Default, Level::Precise);
+    match codec {
+        Compression::None => Box::new(BufWriter::new(inner)),
+        Compression::Gzip => Box::new(BufWriter::new(GzipEncoder::with_quality(inner, level))),
+        Compression::Zstd => Box::new(BufWriter::new(ZstdEncoder::with_quality(inner, level))),
+        Compression::Xz => Box::new(BufWriter::new(XzEncoder::with_quality(inner, level))),
+        Compression::Bzip2 => Box::new(BufWriter::new(BzEncoder::with_quality(inner, level))),
+        Compression::Lz4 => Box::new(BufWriter::new(Lz4Encoder::with_quality(inner, level))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+
+    async fn write_compressed(path: &Path, codec: Compression, chunks: &[&str]) {
+        // Each chunk becomes its own gzip member / zstd frame
+        let mut bytes = Vec::new();
+        for chunk in chunks {
+            let tmp = path.with_extension("part");
+            let mut writer = output_writer(File::create(&tmp).await.unwrap(), codec, None);
+            writer.write_all(chunk.as_bytes()).await.unwrap();
+            writer.shutdown().await.unwrap();
+            bytes.extend(tokio::fs::read(&tmp).await.unwrap());
+        }
+        tokio::fs::write(path, bytes).await.unwrap();
+    }
+
+    async fn read_all(path: &Path) -> String {
+        let mut out = String::new();
+        open_input(path).await.unwrap().read_to_string(&mut out).await.unwrap();
+        out
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Compression::from_path(&PathBuf::from("a.jsonl")), Compression::None);
+        assert_eq!(Compression::from_path(&PathBuf::from("a.jsonl.gz")), Compression::Gzip);
+        assert_eq!(Compression::from_path(&PathBuf::from("a.jsonl.zst")), Compression::Zstd);
+        assert_eq!(Compression::from_path(&PathBuf::from("a.jsonl.zstd")), Compression::Zstd);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert_eq!("GZIP".parse::<Compression>().unwrap(), Compression::Gzip);
+        assert!("rar".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_zstd_frame_content_size() {
+        // Single segment frame with a one byte content size of 42
+        assert_eq!(zstd_frame_content_size(&[0x28, 0xb5, 0x2f, 0xfd, 0x20, 42]), Some(42));
+        // Windowed frame with a two byte content size (stored minus 256)
+        assert_eq!(zstd_frame_content_size(&[0x28, 0xb5, 0x2f, 0xfd, 0x40, 0x58, 0x00, 0x01]), Some(512));
+        // Streaming frame without a content size
+        assert_eq!(zstd_frame_content_size(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x58]), None);
+        assert_eq!(zstd_frame_content_size(b"not zstd"), None);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_uncompressed_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let content: String = (0..2000).map(|i| format!("{{\"id\": {}}}\n", i)).collect();
+        for codec in Compression::ALL {
+            let path = temp_dir.path().join(format!("data.jsonl.{}", codec.extension().unwrap_or("txt")));
+            write_compressed(&path, codec, &[&content]).await;
+            // Small files are decoded whole, so the estimate is exact
+            assert_eq!(estimate_uncompressed_size(&path).await.unwrap(), content.len() as u64, "codec {}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_frame_zstd_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.jsonl.zst");
+        write_compressed(&path, Compression::Zstd, &["a\nb\n", "c\n"]).await;
+        assert_eq!(read_all(&path).await, "a\nb\nc\n");
+    }
+
+    #[tokio::test]
+    async fn test_long_window_zstd() {
+        use async_compression::zstd::CParameter;
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("long.jsonl.zst");
+        let file = File::create(&path).await.unwrap();
+        let mut writer = ZstdEncoder::with_quality_and_params(
+            file,
+            Level::Default,
+            &[CParameter::window_log(30), CParameter::enable_long_distance_matching(true)],
+        );
+        writer.write_all(b"x\ny\n").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        assert_eq!(read_all(&path).await, "x\ny\n");
+    }
+
+    #[tokio::test]
+    async fn test_every_codec_round_trips_with_level() {
+        let temp_dir = TempDir::new().unwrap();
+        for codec in Compression::ALL {
+            let name = match codec.extension() {
+                Some(ext) => format!("data.jsonl.{}", ext),
+                None => "data.jsonl".to_string(),
+            };
+            let path = temp_dir.path().join(name);
+            let mut writer = output_writer(File::create(&path).await.unwrap(), codec, Some(3));
+            writer.write_all(b"{\"a\": 1}\n").await.unwrap();
+            writer.shutdown().await.unwrap();
+
+            assert_eq!(Compression::from_path(&path), codec);
+            assert_eq!(read_all(&path).await, "{\"a\": 1}\n", "codec {}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_member_gzip_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.jsonl.gz");
+        write_compressed(&path, Compression::Gzip, &["a\n", "b\n"]).await;
+        assert_eq!(read_all(&path).await, "a\nb\n");
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Stage of a run an error happened in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Validating the config, checking the journal and planning buckets
+    Setup,
+    /// Phase 1: scattering input records into temp buckets
+    Distribute,
+    /// Phase 2: shuffling buckets and writing output shards
+    Shuffle,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Setup => "setup",
+            Phase::Distribute => "phase 1",
+            Phase::Shuffle => "phase 2",
+        })
+    }
+}
+
+/// Why a shuffle failed.
+#[derive(Debug)]
+pub enum ShuffleError {
+    /// A setting is missing, out of range or inconsistent with another
+    InvalidConfig(String),
+    /// An input file does not exist
+    InputNotFound { path: PathBuf },
+    /// An input could not be decoded: a corrupt or truncated compressed
+    /// stream, or a record that isn't valid UTF-8
+    MalformedInput {
+        path: PathBuf,
+        /// Records read from the input before the error
+        record: u64,
+        source: io::Error,
+    },
+    /// A single record is larger than an output shard may be
+    RecordTooLarge { bytes: u64, limit: u64 },
+    /// A disk is full, or too small for the run's temp files
+    StorageFull { phase: Phase, path: Option<PathBuf>, source: io::Error },
+    /// The journal of an interrupted run with different inputs or settings
+    /// is in the way
+    JournalConflict { path: PathBuf },
+    /// Any other I/O failure
+    Io { phase: Phase, path: Option<PathBuf>, source: io::Error },
+    /// The run was cancelled before it finished
+    Cancelled,
+}
+
+impl ShuffleError {
+    /// Failure to open the input at `path`.
+    pub(crate) fn opening_input(path: &Path, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::NotFound => ShuffleError::InputNotFound { path: path.to_path_buf() },
+            _ => Self::io(Some(path), source),
+        }
+    }
+
+    /// Failure reading the input at `path` after `record` records.
+    pub(crate) fn reading_input(path: &Path, record: u64, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ShuffleError::MalformedInput {
+                path: path.to_path_buf(),
+                record,
+                source,
+            },
+            _ => Self::io(Some(path), source),
+        }
+    }
+
+    /// An I/O error, attributed to a phase later by `in_phase`.
+    fn io(path: Option<&Path>, source: io::Error) -> Self {
+        let path = path.map(Path::to_path_buf);
+        match source.kind() {
+            io::ErrorKind::StorageFull => ShuffleError::StorageFull { phase: Phase::Setup, path, source },
+            _ => ShuffleError::Io { phase: Phase::Setup, path, source },
+        }
+    }
+
+    /// Attribute an I/O error to `phase`; errors only get their phase once
+    /// they reach the top of the phase they happened in.
+    pub(crate) fn in_phase(mut self, phase: Phase) -> Self {
+        if let ShuffleError::StorageFull { phase: p, .. } | ShuffleError::Io { phase: p, .. } = &mut self {
+            *p = phase;
+        }
+        self
+    }
+}
+
+impl fmt::Display for ShuffleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShuffleError::InvalidConfig(message) => f.write_str(message),
+            ShuffleError::InputNotFound { path } => write!(f, "input file not found: {}", path.display()),
+            ShuffleError::MalformedInput { path, record, source } => {
+                write!(f, "malformed input {} after {} records: {}", path.display(), record, source)
+            }
+            ShuffleError::RecordTooLarge { bytes, limit } => {
+                write!(f, "a {} byte record does not fit in a {} byte shard", bytes, limit)
+            }
+            ShuffleError::StorageFull { phase, path, source } | ShuffleError::Io { phase, path, source } => {
+                match path {
+                    Some(path) => write!(f, "{} failed on {}: {}", phase, path.display(), source),
+                    None => write!(f, "{} failed: {}", phase, source),
+                }
+            }
+            ShuffleError::JournalConflict { path } => write!(
+                f,
+                "{} belongs to an interrupted run with different inputs or settings; \
+                 delete it and that run's temp files to start over",
+                path.display()
+            ),
+            ShuffleError::Cancelled => f.write_str("shuffle cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ShuffleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShuffleError::MalformedInput { source, .. }
+            | ShuffleError::StorageFull { source, .. }
+            | ShuffleError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// An I/O error without a known path.
+impl From<io::Error> for ShuffleError {
+    fn from(source: io::Error) -> Self {
+        Self::io(None, source)
+    }
+}
+
+impl From<ShuffleError> for io::Error {
+    fn from(error: ShuffleError) -> Self {
+        let kind = match &error {
+            ShuffleError::InvalidConfig(_) => io::ErrorKind::InvalidInput,
+            ShuffleError::InputNotFound { .. } => io::ErrorKind::NotFound,
+            ShuffleError::MalformedInput { .. } | ShuffleError::RecordTooLarge { .. } => io::ErrorKind::InvalidData,
+            ShuffleError::StorageFull { source, .. } | ShuffleError::Io { source, .. } => source.kind(),
+            ShuffleError::JournalConflict { .. } => io::ErrorKind::AlreadyExists,
+            ShuffleError::Cancelled => io::ErrorKind::Interrupted,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Attaches the path an I/O operation was working on to its error.
+pub(crate) trait IoContext<T> {
+    fn at(self, path: &Path) -> Result<T, ShuffleError>;
+}
+
+impl<T> IoContext<T> for Result<T, io::Error> {
+    fn at(self, path: &Path) -> Result<T, ShuffleError> {
+        self.map_err(|source| ShuffleError::io(Some(path), source))
+    }
+}
@@ -0,0 +1,261 @@
+use std::io;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::ShuffleError;
+use crate::mixing;
+use crate::seeding;
+use crate::shuffle::ShuffleConfig;
+
+/// What ties records into groups that are shuffled as units: all records
+/// with the same value form one group, wherever they are in the inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKey {
+    /// Dot-separated path of a JSON field (e.g. `meta.conversation_id`)
+    Field(String),
+    /// Regex over the raw record, grouping by its first capture group, or by
+    /// the whole match if it has no capture groups
+    Pattern(String),
+}
+
+/// Check the group key of a config.
+pub(crate) fn validate(config: &ShuffleConfig) -> Result<(), ShuffleError> {
+    let invalid = |message: &str| Err(ShuffleError::InvalidConfig(message.to_string()));
+    if config.group_by.is_some() && mixing::mixes(config) {
+        return invalid("group_by can't be combined with source groups or repeats, which pick records one by one");
+    }
+    // Groups pick their own split unless the split key is the group field
+    match (&config.group_by, &config.split_key) {
+        (Some(GroupKey::Field(field)), Some(split_key)) if field == split_key => {}
+        (Some(_), Some(_)) => return invalid("a split_key other than the group_by field could split groups up; leave it out"),
+        _ => {}
+    }
+    config.group_by.as_ref().map_or(Ok(()), |group_by| Grouper::new(group_by).map(drop))
+}
+
+/// Finds the group of each record.
+///
+/// Records of a group share one sort key, derived from a hash of their
+/// group's value in place of their input and position, so phase 1 sends
+/// them all to the same bucket and phase 2's sort keeps them together,
+/// breaking the tie by input order.
+#[derive(Debug, Clone)]
+pub(crate) enum Grouper {
+    Field(Vec<String>),
+    Pattern(Regex),
+}
+
+impl Grouper {
+    pub(crate) fn new(group_by: &GroupKey) -> Result<Self, ShuffleError> {
+        match group_by {
+            GroupKey::Field(path) => match field_path(path) {
+                Some(path) => Ok(Self::Field(path)),
+                None => Err(ShuffleError::InvalidConfig(format!("bad group field '{}'", path))),
+            },
+            GroupKey::Pattern(pattern) => Regex::new(pattern)
+                .map(Self::Pattern)
+                .map_err(|e| ShuffleError::InvalidConfig(format!("bad group pattern: {}", e))),
+        }
+    }
+
+    /// Hash of the group of a non-blank record. Fails if the record has no
+    /// group: it lacks the field (or isn't JSON), or the pattern doesn't
+    /// match it, or its first capture group doesn't take part in the match.
+    pub(crate) fn group_hash(&self, record: &str) -> Result<u64, io::Error> {
+        match self {
+            Self::Field(path) => field_hash(record, path),
+            Self::Pattern(pattern) => {
+                let group = match pattern.captures_len() {
+                    1 => 0,
+                    _ => 1,
+                };
+                let value = pattern.captures(record).and_then(|captures| captures.get(group)).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("record has no group for pattern '{}'", pattern))
+                })?;
+                Ok(seeding::hash_bytes(value.as_str().as_bytes()))
+            }
+        }
+    }
+}
+
+/// The object keys along a dot-separated field path, if none is empty.
+pub(crate) fn field_path(path: &str) -> Option<Vec<String>> {
+    let parts: Vec<String> = path.split('.').map(String::from).collect();
+    (!parts.iter().any(String::is_empty)).then_some(parts)
+}
+
+/// Hash of the value at `path` (a path of object keys) in a JSON record:
+/// of the text of a string, or of the compact JSON of anything else.
+pub(crate) fn field_hash(record: &str, path: &[String]) -> Result<u64, io::Error> {
+    let missing = || io::Error::new(io::ErrorKind::InvalidData, format!("record has no field '{}'", path.join(".")));
+    let parsed: Value = serde_json::from_str(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut value = &parsed;
+    for part in path {
+        value = value.get(part).ok_or_else(missing)?;
+    }
+    Ok(match value {
+        Value::String(text) => seeding::hash_bytes(text.as_bytes()),
+        other => seeding::hash_bytes(other.to_string().as_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::shuffle::shuffle_files;
+    use crate::split::Split;
+
+    #[test]
+    fn test_group_hash_by_field_and_pattern() {
+        let field = Grouper::new(&GroupKey::Field("meta.doc".to_string())).unwrap();
+        let pattern = Grouper::new(&GroupKey::Pattern(r#""doc": "([^"]*)""#.to_string())).unwrap();
+        let whole_match = Grouper::new(&GroupKey::Pattern("doc-[0-9]+".to_string())).unwrap();
+        let record = r#"{"meta": {"doc": "doc-7"}, "chunk": 0}"#;
+        let hash = field.group_hash(record).unwrap();
+        assert_eq!(pattern.group_hash(record).unwrap(), hash);
+        assert_eq!(whole_match.group_hash(record).unwrap(), hash);
+        assert_eq!(field.group_hash(r#"{"chunk": 1, "meta": {"doc": "doc-7"}}"#).unwrap(), hash);
+        assert_ne!(field.group_hash(r#"{"meta": {"doc": "doc-8"}}"#).unwrap(), hash);
+
+        assert!(field.group_hash(r#"{"meta": {}}"#).is_err());
+        assert!(pattern.group_hash("no document here").is_err());
+        // An optional capture group that doesn't take part is no group, not the whole match
+        let optional = Grouper::new(&GroupKey::Pattern("doc(?:=([0-9]+))?".to_string())).unwrap();
+        assert_eq!(optional.group_hash("doc=7").unwrap(), seeding::hash_bytes(b"7"));
+        assert!(optional.group_hash("doc").is_err());
+        assert!(Grouper::new(&GroupKey::Field("meta..doc".to_string())).is_err());
+        assert!(Grouper::new(&GroupKey::Pattern("(".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_larger_than_memory_budget() {
+        let temp_dir = TempDir::new().unwrap();
+        // One 3 MB conversation among small ones, with a 1 MB budget
+        let padding = "x".repeat(300);
+        let content: String = (0..10_000)
+            .map(|i| match i % 5 {
+                0 => format!("{{\"conversation\": {}, \"turn\": 0, \"text\": \"{}\"}}\n", i + 1, padding),
+                _ => format!("{{\"conversation\": 0, \"turn\": {}, \"text\": \"{}\"}}\n", i - i / 5 - 1, padding),
+            })
+            .collect();
+        let input = temp_dir.path().join("input.jsonl");
+        fs::write(&input, content).unwrap();
+        let mut config = ShuffleConfig::new(vec![input], temp_dir.path().join("out").to_str().unwrap(), "data", 64, "\n", "jsonl", Some(5)).unwrap();
+        config.group_by = Some(GroupKey::Field("conversation".to_string()));
+        config.memory_budget_mb = 1;
+
+        let report = shuffle_files(&config).await.unwrap();
+        let records: Vec<Value> = report
+            .output_paths()
+            .iter()
+            .flat_map(|path| fs::read_to_string(path).unwrap().lines().map(String::from).collect::<Vec<_>>())
+            .map(|line| serde_json::from_str(&line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 10_000);
+        // The big conversation is one run of turns, in order
+        let start = records.iter().position(|value| value["conversation"] == 0).unwrap();
+        let turns: Vec<u64> = records[start..start + 8_000].iter().map(|value| value["turn"].as_u64().unwrap()).collect();
+        assert_eq!(turns, (0..8_000).collect::<Vec<u64>>());
+        assert!(records[start..start + 8_000].iter().all(|value| value["conversation"] == 0));
+    }
+
+    #[test]
+    fn test_validate_split_key() {
+        let split = |split_key: &str| {
+            let mut config = ShuffleConfig::new(Vec::new(), std::env::temp_dir().to_str().unwrap(), "group", 64, "\n", "jsonl", None).unwrap();
+            config.splits = vec![Split { name: "a".to_string(), fraction: 1.0 }, Split { name: "b".to_string(), fraction: 1.0 }];
+            config.split_key = Some(split_key.to_string());
+            config
+        };
+        let mut config = split("doc");
+        config.group_by = Some(GroupKey::Field("doc".to_string()));
+        assert!(validate(&config).is_ok());
+        config.group_by = Some(GroupKey::Field("id".to_string()));
+        assert!(matches!(validate(&config), Err(ShuffleError::InvalidConfig(_))));
+        config.group_by = Some(GroupKey::Pattern("doc-([0-9]+)".to_string()));
+        assert!(matches!(validate(&config), Err(ShuffleError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_groups_stay_together_in_order() {
+        let temp_dir = TempDir::new().unwrap();
+        // Conversations of 1 to 6 turns, some continued in the second input,
+        // filling a few 1 MB buckets
+        let mut turns = HashMap::new();
+        let mut inputs = Vec::new();
+        let padding = "x".repeat(300);
+        for (index, range) in [0..800, 600..1_000].into_iter().enumerate() {
+            let mut content = String::new();
+            for conversation in range {
+                for _ in 0..conversation % 6 + 1 {
+                    let turn = turns.entry(conversation).or_insert(0);
+                    content.push_str(&format!("{{\"conversation\": {}, \"turn\": {}, \"text\": \"{}\"}}\n", conversation, turn, padding));
+                    *turn += 1;
+                }
+            }
+            let path = temp_dir.path().join(format!("input_{}.jsonl", index));
+            fs::write(&path, content).unwrap();
+            inputs.push(path);
+        }
+        let total: usize = turns.values().sum();
+
+        let run = |group_by: GroupKey, splits: Vec<Split>, name: &str| {
+            let mut config = ShuffleConfig::new(inputs.clone(), temp_dir.path().join(name).to_str().unwrap(), "data", 1, "\n", "jsonl", Some(5)).unwrap();
+            config.group_by = Some(group_by);
+            config.splits = splits;
+            async move { shuffle_files(&config).await.unwrap() }
+        };
+        let parse = |line: &str| -> (u64, u64) {
+            let value: Value = serde_json::from_str(line).unwrap();
+            (value["conversation"].as_u64().unwrap(), value["turn"].as_u64().unwrap())
+        };
+
+        let report = run(GroupKey::Field("conversation".to_string()), Vec::new(), "field").await;
+        assert!(report.outputs.len() > 1);
+        let records: Vec<(u64, u64)> = report
+            .output_paths()
+            .iter()
+            .flat_map(|path| fs::read_to_string(path).unwrap().lines().map(parse).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(records.len(), total);
+        // Every conversation is one run of turns, in order
+        let mut seen = Vec::new();
+        for (index, &(conversation, turn)) in records.iter().enumerate() {
+            match turn {
+                0 => seen.push(conversation),
+                _ => assert_eq!(records[index - 1], (conversation, turn - 1)),
+            }
+        }
+        assert_eq!(seen.len(), turns.len());
+        assert!(seen.windows(2).filter(|pair| pair[0] < pair[1]).count() < seen.len() * 3 / 4, "conversations weren't shuffled");
+
+        // A pattern capturing the same text groups identically
+        let by_pattern = run(GroupKey::Pattern(r#""conversation": ([0-9]+)"#.to_string()), Vec::new(), "pattern").await;
+        let contents = |report: &crate::ShuffleReport| -> Vec<String> {
+            report.output_paths().iter().map(|path| fs::read_to_string(path).unwrap()).collect()
+        };
+        assert_eq!(contents(&by_pattern), contents(&report));
+
+        // Splits keep conversations whole too
+        let splits = vec![Split { name: "a".to_string(), fraction: 1.0 }, Split { name: "b".to_string(), fraction: 1.0 }];
+        let split = run(GroupKey::Field("conversation".to_string()), splits, "split").await;
+        let conversations = |name: &str| -> Vec<u64> {
+            let mut conversations: Vec<u64> = split
+                .outputs
+                .iter()
+                .filter(|output| output.split.as_deref() == Some(name))
+                .flat_map(|output| fs::read_to_string(&output.path).unwrap().lines().map(|line| parse(line).0).collect::<Vec<_>>())
+                .collect();
+            conversations.dedup();
+            conversations.sort();
+            conversations
+        };
+        let (a, b) = (conversations("a"), conversations("b"));
+        assert!(!a.is_empty() && !b.is_empty());
+        assert!(a.iter().all(|conversation| b.binary_search(conversation).is_err()));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use crate::error::{IoContext, ShuffleError};
+use crate::output::ShardDigest;
+use crate::seeding;
+use crate::shuffle::ShuffleConfig;
+
+/// First word of every journal; bump the version when the format changes.
+const HEADER: &str = "shuffly-journal-v5";
+
+/// Everything a run has recorded about its progress.
+///
+/// The journal is a text file of one event per line, appended and synced as
+/// the run goes. A line cut off by a crash (no trailing newline) is ignored,
+/// so every event is either fully recorded or not at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct JournalState {
+    /// Identifies the inputs and settings the run was started with
+    pub(crate) fingerprint: u64,
+    pub(crate) seed: u64,
+    /// Temp buckets of each output set (see `ShuffleConfig::splits`), which
+    /// take consecutive bucket numbers in set order
+    pub(crate) set_buckets: Vec<usize>,
+    /// Latest durable state of the phase 1 temp buckets
+    pub(crate) checkpoint: Option<Checkpoint>,
+    /// Total records, once phase 1 has finished
+    pub(crate) phase_1_records: Option<usize>,
+    /// Phase 2 buckets written to their own shard (empty if the bucket was)
+    pub(crate) written_buckets: HashMap<usize, ShardDigest>,
+    /// Records each consumed bucket contributed to the shard stream
+    pub(crate) consumed_buckets: HashMap<usize, u64>,
+    /// Shards closed so far in each output set, in order
+    pub(crate) closed_shards: HashMap<usize, Vec<ShardDigest>>,
+}
+
+/// Temp bucket contents as of a checkpoint: anything past `lengths` was
+/// written after it and is discarded on resume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    /// Bytes in each bucket file
+    pub(crate) lengths: Vec<u64>,
+    /// Records in each bucket file
+    pub(crate) records: Vec<u64>,
+    /// Every input (by sorted index) whose records are all in the buckets
+    pub(crate) inputs: Vec<(usize, InputCounts)>,
+}
+
+/// What phase 1 read from one input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct InputCounts {
+    /// Non-blank records read
+    pub(crate) records: u64,
+    /// Records scattered to the buckets of each output set, counting every
+    /// copy a mixture makes
+    pub(crate) scattered: Vec<u64>,
+    /// Uncompressed bytes read, counting a delimiter after every record
+    pub(crate) bytes: u64,
+    /// Blank records, which are dropped
+    pub(crate) blank: u64,
+}
+
+impl InputCounts {
+    pub(crate) fn new(sets: usize) -> Self {
+        Self { scattered: vec![0; sets], ..Default::default() }
+    }
+
+    /// Records scattered to the buckets of all output sets.
+    pub(crate) fn total_scattered(&self) -> u64 {
+        self.scattered.iter().sum()
+    }
+
+    /// Records scattered to the output sets of the first of `epochs` epochs,
+    /// which lead the sets.
+    pub(crate) fn first_epoch_scattered(&self, epochs: usize) -> u64 {
+        self.scattered[..self.scattered.len() / epochs].iter().sum()
+    }
+}
+
+impl JournalState {
+    pub(crate) fn new(fingerprint: u64, seed: u64, set_buckets: Vec<usize>) -> Self {
+        Self {
+            fingerprint,
+            seed,
+            set_buckets,
+            checkpoint: None,
+            phase_1_records: None,
+            written_buckets: HashMap::new(),
+            consumed_buckets: HashMap::new(),
+            closed_shards: HashMap::new(),
+        }
+    }
+
+    /// Temp buckets of all output sets.
+    pub(crate) fn num_buckets(&self) -> usize {
+        self.set_buckets.iter().sum()
+    }
+}
+
+/// Appends events to a run's journal.
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Start a new journal for `state`, replacing any existing one.
+    pub(crate) async fn create(path: &Path, state: &JournalState) -> Result<Self, ShuffleError> {
+        let mut journal = Self {
+            path: path.to_path_buf(),
+            file: File::create(path).await.at(path)?,
+        };
+        journal
+            .write_line(&format!(
+                "{} fingerprint={:016x} seed={} buckets={}",
+                HEADER, state.fingerprint, state.seed, join(&state.set_buckets)
+            ))
+            .await?;
+        Ok(journal)
+    }
+
+    /// Continue an existing journal.
+    pub(crate) async fn append(path: &Path) -> Result<Self, ShuffleError> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: OpenOptions::new().append(true).open(path).await.at(path)?,
+        })
+    }
+
+    pub(crate) async fn record_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), ShuffleError> {
+        let inputs: Vec<String> = checkpoint
+            .inputs
+            .iter()
+            .map(|(i, counts)| {
+                let scattered: Vec<String> = counts.scattered.iter().map(u64::to_string).collect();
+                format!("{}:{}:{}:{}:{}", i, counts.records, scattered.join("+"), counts.bytes, counts.blank)
+            })
+            .collect();
+        self.write_line(&format!(
+            "checkpoint lengths={} records={} inputs={}",
+            join(&checkpoint.lengths),
+            join(&checkpoint.records),
+            inputs.join(",")
+        ))
+        .await
+    }
+
+    pub(crate) async fn record_phase_1(&mut self, records: usize) -> Result<(), ShuffleError> {
+        self.write_line(&format!("phase1 records={}", records)).await
+    }
+
+    pub(crate) async fn record_written_bucket(&mut self, bucket: usize, shard: &ShardDigest) -> Result<(), ShuffleError> {
+        self.write_line(&format!("bucket {} {}", bucket, digest_fields(shard))).await
+    }
+
+    pub(crate) async fn record_consumed_bucket(&mut self, bucket: usize, records: u64) -> Result<(), ShuffleError> {
+        self.write_line(&format!("consumed {} records={}", bucket, records)).await
+    }
+
+    pub(crate) async fn record_closed_shard(&mut self, set: usize, index: usize, shard: &ShardDigest) -> Result<(), ShuffleError> {
+        self.write_line(&format!("shard {} set={} {}", index, set, digest_fields(shard))).await
+    }
+
+    /// Delete the journal once the run has finished.
+    pub(crate) async fn remove(self) -> Result<(), ShuffleError> {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path).await.at(&self.path)
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), ShuffleError> {
+        // One write per event keeps a torn line at the very end of the file
+        self.file.write_all(format!("{}\n", line).as_bytes()).await.at(&self.path)?;
+        self.file.sync_data().await.at(&self.path)
+    }
+}
+
+/// Where the journal for `config`'s run lives.
+pub(crate) fn journal_path(config: &ShuffleConfig) -> PathBuf {
+    config.output_dir.join(format!(".{}_journal", config.output_name))
+}
+
+/// Read the journal at `path`, or `None` if there is none.
+pub(crate) async fn read(path: &Path) -> Result<Option<JournalState>, ShuffleError> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).at(path),
+    };
+    parse(&text)
+        .map(Some)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, format!("corrupt journal: {}", message)))
+        .at(path)
+}
+
+fn parse(text: &str) -> Result<JournalState, String> {
+    // Only newline-terminated lines were fully written
+    let complete = &text[..text.rfind('\n').map_or(0, |i| i + 1)];
+    let mut lines = complete.lines();
+
+    let header = lines.next().ok_or("missing header")?;
+    let mut words = header.split_whitespace();
+    if words.next() != Some(HEADER) {
+        return Err(format!("unsupported header '{}'", header));
+    }
+    let fingerprint = u64::from_str_radix(field(&mut words, "fingerprint")?, 16).map_err(|e| e.to_string())?;
+    let seed = number(field(&mut words, "seed")?)?;
+    let set_buckets = list(field(&mut words, "buckets")?)?.into_iter().map(|buckets| buckets as usize).collect();
+    let mut state = JournalState::new(fingerprint, seed, set_buckets);
+
+    for line in lines {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("checkpoint") => {
+                let lengths = list(field(&mut words, "lengths")?)?;
+                let records = list(field(&mut words, "records")?)?;
+                let inputs = field(&mut words, "inputs")?
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|entry| match entry.split(':').collect::<Vec<_>>()[..] {
+                        [input, records, scattered, bytes, blank] => Ok((
+                            number(input)?,
+                            InputCounts {
+                                records: number(records)?,
+                                scattered: scattered.split('+').map(number).collect::<Result<_, _>>()?,
+                                bytes: number(bytes)?,
+                                blank: number(blank)?,
+                            },
+                        )),
+                        _ => Err(format!("bad input entry '{}'", entry)),
+                    })
+                    .collect::<Result<_, String>>()?;
+                if lengths.len() != state.num_buckets() || records.len() != state.num_buckets() {
+                    return Err("checkpoint does not cover every bucket".to_string());
+                }
+                state.checkpoint = Some(Checkpoint { lengths, records, inputs });
+            }
+            Some("phase1") => state.phase_1_records = Some(number(field(&mut words, "records")?)?),
+            Some("bucket") => {
+                let bucket = number(words.next().ok_or("missing bucket")?)?;
+                state.written_buckets.insert(bucket, digest(&mut words)?);
+            }
+            Some("consumed") => {
+                let bucket = number(words.next().ok_or("missing bucket")?)?;
+                state.consumed_buckets.insert(bucket, number(field(&mut words, "records")?)?);
+            }
+            Some("shard") => {
+                let shard: usize = number(words.next().ok_or("missing shard")?)?;
+                let closed = state.closed_shards.entry(number(field(&mut words, "set")?)?).or_default();
+                if shard != closed.len() {
+                    return Err(format!("shard {} recorded out of order", shard));
+                }
+                closed.push(digest(&mut words)?);
+            }
+            _ => return Err(format!("unexpected line '{}'", line)),
+        }
+    }
+    Ok(state)
+}
+
+fn digest_fields(shard: &ShardDigest) -> String {
+    format!("records={} bytes={} sha256={} groups={}", shard.records, shard.bytes, shard.sha256, join(&shard.groups))
+}
+
+fn digest<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<ShardDigest, String> {
+    Ok(ShardDigest {
+        records: number(field(words, "records")?)?,
+        bytes: number(field(words, "bytes")?)?,
+        sha256: field(words, "sha256")?.to_string(),
+        groups: list(field(words, "groups")?)?,
+    })
+}
+
+/// The value of the next `name=value` word.
+fn field<'a>(words: &mut impl Iterator<Item = &'a str>, name: &str) -> Result<&'a str, String> {
+    words
+        .next()
+        .and_then(|word| word.strip_prefix(name))
+        .and_then(|rest| rest.strip_prefix('='))
+        .ok_or(format!("missing {}", name))
+}
+
+fn number<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("bad number '{}'", s))
+}
+
+fn list(s: &str) -> Result<Vec<u64>, String> {
+    s.split(',').filter(|s| !s.is_empty()).map(number).collect()
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values.iter().map(T::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Hash of everything that decides a run's temp data and output: the sorted
+/// inputs with their sizes and modification times, and every setting except
+/// those (like concurrency or the memory budget) that only affect how the
+/// work is done.
+pub(crate) async fn fingerprint(config: &ShuffleConfig) -> Result<u64, ShuffleError> {
+    let mut inputs = config.input_files.clone();
+    inputs.sort();
+
+    let mut description = String::new();
+    for input in &inputs {
+        let metadata = tokio::fs::metadata(input).await.map_err(|e| ShuffleError::opening_input(input, e))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        description.push_str(&format!("{:?} {} {:?}\n", input, metadata.len(), modified));
+    }
+    description.push_str(&format!(
+        "{:?} {} {:?} {:?} {:?} {} {:?} {:?} {:?} {:?} {:?} {} {:?} {:?} {:?} {} {:?} {:?} {:?}",
+        config.output_dir,
+        config.output_name,
+        config.max_size_mb,
+        config.delimiter,
+        config.file_extension,
+        config.seed.map_or("random".to_string(), |s| s.to_string()),
+        config.output_compression,
+        config.output_compression_level,
+        config.size_basis,
+        config.shard_mode,
+        config.temp_dirs,
+        config.provenance,
+        config.source_groups,
+        config.mixture_records,
+        config.repeats,
+        config.epochs,
+        config.splits,
+        config.split_key,
+        config.group_by,
+    ));
+    Ok(seeding::hash_bytes(description.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip_and_torn_last_line() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("journal");
+
+        let mut expected = JournalState::new(0xabcdef, 42, vec![2, 1]);
+        let mut journal = Journal::create(&path, &expected).await.unwrap();
+        let counts = |records| InputCounts { records, scattered: vec![records, records * 2], bytes: records * 10, blank: 1 };
+        let checkpoint = Checkpoint {
+            lengths: vec![10, 0, 30],
+            records: vec![1, 0, 3],
+            inputs: vec![(0, counts(2)), (2, counts(2))],
+        };
+        let shard = |records| ShardDigest { records, bytes: 42, sha256: "00ff".to_string(), groups: vec![records as u64, 0] };
+        journal.record_checkpoint(&checkpoint).await.unwrap();
+        journal.record_phase_1(4).await.unwrap();
+        journal.record_consumed_bucket(0, 1).await.unwrap();
+        journal.record_closed_shard(0, 0, &shard(1)).await.unwrap();
+        journal.record_closed_shard(1, 0, &shard(2)).await.unwrap();
+        journal.record_written_bucket(2, &shard(3)).await.unwrap();
+        journal.record_written_bucket(1, &ShardDigest::default()).await.unwrap();
+        drop(journal);
+
+        // A crash in the middle of appending leaves a partial line behind
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(b"consumed 1 rec");
+        std::fs::write(&path, bytes).unwrap();
+
+        expected.checkpoint = Some(checkpoint);
+        expected.phase_1_records = Some(4);
+        expected.consumed_buckets.insert(0, 1);
+        expected.closed_shards.insert(0, vec![shard(1)]);
+        expected.closed_shards.insert(1, vec![shard(2)]);
+        expected.written_buckets.insert(2, shard(3));
+        expected.written_buckets.insert(1, ShardDigest::default());
+        assert_eq!(read(&path).await.unwrap(), Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_missing_journal() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(read(&temp_dir.path().join("journal")).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_rejects_unknown_header() {
+        assert!(parse("something else\n").is_err());
+    }
+}
@@ -1,18 +1,122 @@
+mod bucket;
+mod compression;
+mod error;
+mod grouping;
+mod journal;
+mod mixing;
+mod output;
+mod progress;
+mod provenance;
+mod records;
+mod report;
+mod seeding;
 mod shuffle;
+mod split;
+mod verify;
 
 // Re-export your core functions
+pub use compression::Compression;
+pub use error::{Phase, ShuffleError};
+pub use grouping::GroupKey;
+pub use mixing::SourceGroup;
+pub use progress::{Progress, ProgressObserver};
+pub use provenance::unshuffle;
+pub use seeding::epoch_seed;
+pub use report::{manifest_path, GroupStats, InputStats, OutputStats, PhaseTimes, RunSettings, ShuffleReport, SplitStats};
 pub use shuffle::*;
+pub use split::Split;
+pub use tokio_util::sync::CancellationToken;
+pub use verify::{verify, verify_manifest, Mismatch, RecordDigest, Verification};
 
 // Python bindings - only when pyo3 feature enabled
 #[cfg(feature = "pyo3")]
 use pyo3::prelude::*;
 #[cfg(feature = "pyo3")]
 use std::path::PathBuf;
+#[cfg(feature = "pyo3")]
+use std::collections::HashMap;
+
+// Python exception classes, one per `ShuffleError` variant
+#[cfg(feature = "pyo3")]
+mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    create_exception!(shuffly, ShuffleError, PyException, "Base class of the errors raised by shuffle_files.");
+    create_exception!(shuffly, InvalidConfigError, ShuffleError, "A setting is missing, out of range or inconsistent.");
+    create_exception!(shuffly, InputNotFoundError, ShuffleError, "An input file does not exist.");
+    create_exception!(shuffly, MalformedInputError, ShuffleError, "An input is corrupt or not valid UTF-8.");
+    create_exception!(shuffly, RecordTooLargeError, ShuffleError, "A record is larger than an output shard may be.");
+    create_exception!(shuffly, StorageFullError, ShuffleError, "A disk is full or too small for the temp files.");
+    create_exception!(shuffly, JournalConflictError, ShuffleError, "An interrupted run with other settings is in the way.");
+    create_exception!(shuffly, ShuffleIOError, ShuffleError, "Any other I/O failure.");
+}
+
+#[cfg(feature = "pyo3")]
+fn to_py_err(error: ShuffleError) -> PyErr {
+    let message = error.to_string();
+    match error {
+        ShuffleError::InvalidConfig(_) => exceptions::InvalidConfigError::new_err(message),
+        ShuffleError::InputNotFound { .. } => exceptions::InputNotFoundError::new_err(message),
+        ShuffleError::MalformedInput { .. } => exceptions::MalformedInputError::new_err(message),
+        ShuffleError::RecordTooLarge { .. } => exceptions::RecordTooLargeError::new_err(message),
+        ShuffleError::StorageFull { .. } => exceptions::StorageFullError::new_err(message),
+        ShuffleError::JournalConflict { .. } => exceptions::JournalConflictError::new_err(message),
+        ShuffleError::Io { .. } => exceptions::ShuffleIOError::new_err(message),
+        ShuffleError::Cancelled => pyo3::exceptions::PyKeyboardInterrupt::new_err(message),
+    }
+}
+
+/// Calls a Python callable with each progress report as a dict, taking the
+/// GIL for the call. The first exception it raises cancels the shuffle and
+/// is re-raised once the shuffle has cleaned up.
+#[cfg(feature = "pyo3")]
+struct PyProgressObserver {
+    callback: PyObject,
+    cancel: CancellationToken,
+    error: std::sync::Mutex<Option<PyErr>>,
+}
+
+#[cfg(feature = "pyo3")]
+impl ProgressObserver for PyProgressObserver {
+    fn on_progress(&self, progress: &Progress) {
+        Python::with_gil(|py| {
+            let report = pyo3::types::PyDict::new(py);
+            let phase = match progress.phase {
+                Phase::Setup => "setup",
+                Phase::Distribute => "distribute",
+                Phase::Shuffle => "shuffle",
+            };
+            let result = report.set_item("phase", phase)
+                .and_then(|_| report.set_item("bytes", progress.bytes))
+                .and_then(|_| report.set_item("total_bytes", progress.total_bytes))
+                .and_then(|_| report.set_item("records", progress.records))
+                .and_then(|_| report.set_item("total_records", progress.total_records))
+                .and_then(|_| report.set_item("current_file", progress.current_file.as_ref().map(|p| p.to_string_lossy())))
+                .and_then(|_| self.callback.call1(py, (report,)));
+            if let Err(e) = result {
+                self.error.lock().unwrap().get_or_insert(e);
+                self.cancel.cancel();
+            }
+        });
+    }
+}
 
+/// Shuffle the inputs as `shuffle_files` does, returning its report as a
+/// dict of the same fields as the run's manifest.
 #[cfg(feature = "pyo3")]
 #[pyfunction]
 #[pyo3(name = "shuffle_files")]
+#[pyo3(signature = (
+    input_files, output_dir, output_name, max_size_mb, delimiter=None, file_extension=None, seed=None,
+    compression=None, compression_level=None, size_basis=None, memory_budget_mb=None, num_shards=None,
+    strict_size=None, concurrency=None, temp_dirs=None, keep_temp_files=None, progress=None, provenance=None,
+    source_groups=None, mixture_records=None, repeats=None, epochs=None, splits=None, split_key=None,
+    group_by=None, group_by_regex=None,
+))]
+#[allow(clippy::too_many_arguments)]
 fn shuffle_files_py(
+    py: Python<'_>,
     input_files: Vec<String>,  // Changed from &str to Vec<String>
     output_dir: &str,
     output_name: &str,
@@ -20,11 +124,41 @@ fn shuffle_files_py(
     delimiter: Option<&str>,      // Added delimiter parameter
     file_extension: Option<&str>, // Added file extension parameter
     seed: Option<u64>,           // Added seed parameter
-) -> PyResult<Vec<String>> {
+    compression: Option<&str>,   // Output compression (none, gzip, zstd, xz, bz2, lz4)
+    compression_level: Option<i32>,
+    size_basis: Option<&str>,    // "compressed" or "uncompressed"
+    memory_budget_mb: Option<usize>,
+    num_shards: Option<usize>,   // Exactly this many equally sized shards, shared out between splits
+    strict_size: Option<bool>,   // Bound every shard by max_size_mb
+    concurrency: Option<usize>,  // Buckets shuffled in parallel
+    temp_dirs: Option<Vec<String>>, // Temp bucket directories, striped round-robin
+    keep_temp_files: Option<bool>, // Keep temp files of a failed run to resume it
+    progress: Option<PyObject>,  // Called with a dict describing the run's progress
+    provenance: Option<bool>,    // Write a sidecar per shard mapping records back to their inputs
+    source_groups: Option<Vec<(String, f64, Vec<String>)>>, // (name, weight, files) to mix by weight
+    mixture_records: Option<u64>, // Records to output when mixing
+    repeats: Option<HashMap<String, f64>>, // Input file -> times each of its records is output, fractions allowed
+    epochs: Option<usize>,       // Independent permutations written from one read of the inputs
+    splits: Option<Vec<(String, f64)>>, // (name, fraction) of each split, written to output_dir/name
+    split_key: Option<String>,   // JSON field (dotted path) deciding each record's split
+    group_by: Option<String>,    // JSON field (dotted path) whose records are shuffled as one group
+    group_by_regex: Option<String>, // Regex whose first capture group (or match, without groups) is each record's group
+) -> PyResult<PyObject> {
     // Convert string paths to PathBuf
-    let input_pathbufs: Vec<PathBuf> = input_files.into_iter().map(PathBuf::from).collect();
+    let mut input_pathbufs: Vec<PathBuf> = input_files.into_iter().map(PathBuf::from).collect();
+    let source_groups: Vec<SourceGroup> = source_groups
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, weight, files)| SourceGroup { name, weight, inputs: files.into_iter().map(PathBuf::from).collect() })
+        .collect();
+    // Grouped files needn't be listed as inputs too
+    for input in source_groups.iter().flat_map(|group| &group.inputs) {
+        if !input_pathbufs.contains(input) {
+            input_pathbufs.push(input.clone());
+        }
+    }
     
-    let config = shuffle::ShuffleConfig::new(
+    let mut config = shuffle::ShuffleConfig::new(
         input_pathbufs,
         output_dir,
         output_name,
@@ -32,21 +166,155 @@ fn shuffle_files_py(
         delimiter.unwrap_or("\n"),        // Default to newline
         file_extension.unwrap_or("jsonl"), // Default to jsonl
         seed,
-    ).map_err(|e| pyo3::exceptions::PyIOError::new_err(e.to_string()))?;
+    ).map_err(to_py_err)?;
+    
+    if let Some(compression) = compression {
+        config.output_compression = compression.parse().map_err(to_py_err)?;
+    }
+    config.output_compression_level = compression_level;
+    if let Some(memory_budget_mb) = memory_budget_mb {
+        config.memory_budget_mb = memory_budget_mb;
+    }
+    if let Some(concurrency) = concurrency {
+        config.concurrency = concurrency;
+    }
+    if let Some(temp_dirs) = temp_dirs {
+        config.temp_dirs = temp_dirs.into_iter().map(PathBuf::from).collect();
+    }
+    config.keep_temp_files = keep_temp_files.unwrap_or(false);
+    config.provenance = provenance.unwrap_or(false);
+    config.source_groups = source_groups;
+    config.mixture_records = mixture_records;
+    config.repeats = repeats
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(file, multiplier)| (PathBuf::from(file), multiplier))
+        .collect();
+    config.epochs = epochs.unwrap_or(1);
+    config.splits = splits
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, fraction)| Split { name, fraction })
+        .collect();
+    config.split_key = split_key;
+    config.group_by = match (group_by, group_by_regex) {
+        (Some(_), Some(_)) => {
+            return Err(exceptions::InvalidConfigError::new_err("group_by and group_by_regex are mutually exclusive"));
+        }
+        (Some(field), None) => Some(GroupKey::Field(field)),
+        (None, Some(pattern)) => Some(GroupKey::Pattern(pattern)),
+        (None, None) => None,
+    };
+    config.shard_mode = match (num_shards, strict_size.unwrap_or(false)) {
+        (Some(_), true) => {
+            return Err(exceptions::InvalidConfigError::new_err("num_shards and strict_size are mutually exclusive"));
+        }
+        (Some(num_shards), false) => shuffle::ShardMode::Count(num_shards),
+        (None, true) => shuffle::ShardMode::MaxSize,
+        (None, false) => shuffle::ShardMode::PerBucket,
+    };
+    if let Some(size_basis) = size_basis {
+        config.size_basis = size_basis.parse().map_err(to_py_err)?;
+    }
     
     // Use tokio runtime for async function
     let rt = tokio::runtime::Runtime::new()
         .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
     
-    let output_files = rt.block_on(shuffle::shuffle_files(&config))
-        .map_err(|e| pyo3::exceptions::PyIOError::new_err(e.to_string()))?;
+    let cancel = CancellationToken::new();
+    let observer = progress.map(|callback| {
+        std::sync::Arc::new(PyProgressObserver { callback, cancel: cancel.clone(), error: Default::default() })
+    });
+    if let Some(observer) = &observer {
+        config.progress = Some(observer.clone());
+    }
     
-    Ok(output_files.into_iter().map(|p| p.to_string_lossy().to_string()).collect())
+    // Release the GIL while the shuffle runs, taking it back briefly to poll
+    // for Ctrl-C (or any other pending signal) and cancel the shuffle so it
+    // cleans up before the exception propagates
+    let result = py.allow_threads(|| rt.block_on(async {
+        let shuffle = shuffle::shuffle_files_with_cancel(&config, &cancel);
+        tokio::pin!(shuffle);
+        loop {
+            tokio::select! {
+                result = &mut shuffle => {
+                    return result.map_err(to_py_err);
+                }
+                _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {
+                    if let Err(signal) = Python::with_gil(|py| py.check_signals()) {
+                        cancel.cancel();
+                        let _ = shuffle.await;
+                        return Err(signal);
+                    }
+                }
+            }
+        }
+    }));
+    // An exception from the progress callback is why the shuffle stopped
+    if let Some(error) = observer.and_then(|observer| observer.error.lock().unwrap().take()) {
+        return Err(error);
+    }
+    report_to_py(py, &result?)
+}
+
+/// A report as Python objects, going through its JSON form.
+#[cfg(feature = "pyo3")]
+fn report_to_py(py: Python<'_>, report: &ShuffleReport) -> PyResult<PyObject> {
+    let json = serde_json::to_string(report).map_err(|e| exceptions::ShuffleIOError::new_err(e.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
 }
 
 #[cfg(feature = "pyo3")]
 #[pymodule]
 fn shuffly(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(shuffle_files_py, m)?)?;
+    let py = m.py();
+    m.add("ShuffleError", py.get_type::<exceptions::ShuffleError>())?;
+    m.add("InvalidConfigError", py.get_type::<exceptions::InvalidConfigError>())?;
+    m.add("InputNotFoundError", py.get_type::<exceptions::InputNotFoundError>())?;
+    m.add("MalformedInputError", py.get_type::<exceptions::MalformedInputError>())?;
+    m.add("RecordTooLargeError", py.get_type::<exceptions::RecordTooLargeError>())?;
+    m.add("StorageFullError", py.get_type::<exceptions::StorageFullError>())?;
+    m.add("JournalConflictError", py.get_type::<exceptions::JournalConflictError>())?;
+    m.add("ShuffleIOError", py.get_type::<exceptions::ShuffleIOError>())?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "pyo3"))]
+mod tests {
+    use super::*;
+    use pyo3::types::{PyDict, PyList};
+
+    #[test]
+    fn test_python_gets_the_report() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input = temp_dir.path().join("input.jsonl");
+        std::fs::write(&input, (0..100).map(|i| format!("{{\"id\": {}}}\n", i)).collect::<String>()).unwrap();
+        let output_dir = temp_dir.path().join("out");
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "shuffly").unwrap();
+            shuffly(&module).unwrap();
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("seed", 7).unwrap();
+            kwargs.set_item("num_shards", 2).unwrap();
+            let args = (vec![input.to_string_lossy()], output_dir.to_string_lossy(), "shuffled", 1);
+            let report = module.getattr("shuffle_files").unwrap().call(args, Some(&kwargs)).unwrap();
+
+            assert_eq!(report.get_item("seed").unwrap().extract::<u64>().unwrap(), 7);
+            let inputs = report.get_item("inputs").unwrap();
+            assert_eq!(inputs.get_item(0).unwrap().get_item("records").unwrap().extract::<u64>().unwrap(), 100);
+            let outputs = report.get_item("outputs").unwrap().downcast_into::<PyList>().unwrap();
+            assert_eq!(outputs.len(), 2);
+            let mut records = 0;
+            for output in outputs.iter() {
+                records += output.get_item("records").unwrap().extract::<u64>().unwrap();
+                assert_eq!(output.get_item("sha256").unwrap().extract::<String>().unwrap().len(), 64);
+            }
+            assert_eq!(records, 100);
+            assert!(report.get_item("config").unwrap().get_item("shard_mode").is_ok());
+            assert!(report.get_item("elapsed").unwrap().get_item("shuffle").is_ok());
+        });
+    }
+}
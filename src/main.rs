@@ -1,13 +1,25 @@
-use clap::Parser;
-use shuffly::ShuffleConfig;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use shuffly::{Compression, GroupKey, ShardMode, ShuffleConfig, ShuffleError, SizeBasis, SourceGroup, Split};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
+use tracing_indicatif::filter::{hide_indicatif_span_fields, IndicatifFilter};
+use tracing_indicatif::IndicatifLayer;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::DefaultFields;
+use tracing_subscriber::prelude::*;
 
 #[derive(Parser)]
 #[command(name = "shuffly")]
 #[command(about = "A CLI tool for shuffling JSONL files")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Input files separated by colons (e.g., "file1.jsonl:file2.jsonl")
+    #[command(subcommand)]
+    command: Option<Command>,
+    
+    /// Input files separated by colons (e.g., "file1.jsonl:file2.jsonl"); entries may be glob patterns and
+    /// take a repeat multiplier, fractions allowed (e.g., "books/*.jsonl@3:web.jsonl@0.5")
     #[arg(short = 'f', long, group = "input")]
     input_files: Option<String>,
     
@@ -27,6 +39,7 @@ struct Cli {
     #[arg(short = 's', long, default_value_t = 4096)]
     max_size_mb: usize,
 
+    /// Record delimiter; may be multi-byte and accepts escapes such as \n, \t or \x1e
     #[arg(long, default_value = "\n")]
     delimiter: String,
 
@@ -36,8 +49,206 @@ struct Cli {
     /// Random seed for deterministic shuffling
     #[arg(long)]
     seed: Option<u64>,
+    
+    /// Compression for output files (none, gzip, zstd, xz, bz2 or lz4)
+    #[arg(long, default_value = "none")]
+    compression: Compression,
+    
+    /// Compression level on the codec's own scale (defaults to the codec default)
+    #[arg(long, allow_hyphen_values = true)]
+    compression_level: Option<i32>,
+    
+    /// Whether --max-size-mb limits compressed or uncompressed output size
+    #[arg(long, default_value = "uncompressed")]
+    size_basis: SizeBasis,
+    
+    /// Memory for buffered records in MB, shared by reading, scattering and shuffling buckets
+    #[arg(long, default_value_t = shuffly::DEFAULT_MEMORY_BUDGET_MB)]
+    memory_budget_mb: usize,
+    
+    /// Write exactly this many output files with equal record counts (±1); with --split, they are shared
+    /// out by fraction, at least one per split
+    #[arg(long, conflicts_with = "strict_size")]
+    num_shards: Option<usize>,
+    
+    /// Never let an output file exceed --max-size-mb (instead of one file per temp bucket)
+    #[arg(long)]
+    strict_size: bool,
+    
+    /// Number of inputs to decode and temp files to shuffle in parallel (defaults to the number of CPUs)
+    #[arg(short = 'j', long, default_value_t = shuffly::default_concurrency())]
+    concurrency: usize,
+    
+    /// Directory for temp files (defaults to the output directory); repeat to stripe across several disks
+    #[arg(long = "temp-dir")]
+    temp_dirs: Vec<PathBuf>,
+    
+    /// Keep temp files when a run fails or is interrupted, so rerunning the same command resumes it
+    #[arg(long)]
+    keep_temp: bool,
+    
+    /// Mix a source group into the output at a relative weight, as NAME:WEIGHT:PATHS with colon-separated files
+    /// or directories (e.g. "web:0.6:data/web"); repeat for each group, instead of --input-files or --input-dir
+    #[arg(long = "group", value_name = "NAME:WEIGHT:PATHS", conflicts_with = "input")]
+    groups: Vec<String>,
+    
+    /// Records to output when mixing groups (defaults to the groups' total)
+    #[arg(long, requires = "groups")]
+    mixture_records: Option<u64>,
+    
+    /// Write this many independently shuffled copies of the output from one read of the inputs, epoch E's files
+    /// named as if --output-name were NAME_epochE
+    #[arg(long, default_value_t = 1)]
+    epochs: usize,
+    
+    /// Send a share of the records to a named split, written to a subdirectory of the output directory with its
+    /// name, as NAME:FRACTION (e.g. "train:0.98"); repeat for each split, fractions being relative to each other
+    #[arg(long = "split", value_name = "NAME:FRACTION")]
+    splits: Vec<String>,
+    
+    /// Assign records to splits by this JSON field (dots reach into nested objects), so a record with the same
+    /// value always lands in the same split, whatever the seed
+    #[arg(long, value_name = "FIELD", requires = "splits")]
+    split_key: Option<String>,
+    
+    /// Shuffle groups of records instead of single records: records with the same value of this JSON field (dots
+    /// reach into nested objects) stay adjacent and in input order, e.g. the turns of a conversation
+    #[arg(long, value_name = "FIELD", conflicts_with_all = ["group_by_regex", "groups"])]
+    group_by: Option<String>,
+    
+    /// Like --group-by, grouping by this regex's first capture group (or whole match, without groups) in each raw record
+    #[arg(long, value_name = "REGEX", conflicts_with = "groups")]
+    group_by_regex: Option<String>,
+    
+    /// Write a .provenance sidecar next to each output file recording where every record came from, so `shuffly unshuffle` can undo the run
+    #[arg(long)]
+    provenance: bool,
+    
+    /// Only log warnings and errors, and draw no progress bars
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    
+    /// Log more detail (-v for debug, -vv for trace)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    
+    /// Format of the log on stderr; json draws no progress bars
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that output files hold exactly the records of the inputs, in any order
+    Verify(VerifyArgs),
+    /// Rebuild the inputs of a run made with --provenance from its output files
+    Unshuffle(UnshuffleArgs),
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Manifest of the run to check, which lists its inputs and outputs; file record counts and checksums are checked against it too
+    #[arg(short, long, conflicts_with_all = ["input", "output", "delimiter"])]
+    manifest: Option<PathBuf>,
+    
+    /// Input files separated by colons
+    #[arg(short = 'f', long, group = "input")]
+    input_files: Option<String>,
+    
+    /// Directory containing the input files
+    #[arg(short = 'd', long, group = "input")]
+    input_dir: Option<String>,
+    
+    /// Output files separated by colons
+    #[arg(long, group = "output")]
+    output_files: Option<String>,
+    
+    /// Directory containing the output files
+    #[arg(short, long, group = "output")]
+    output_dir: Option<String>,
+    
+    /// Record delimiter, as for shuffling
+    #[arg(long, default_value = "\n")]
+    delimiter: String,
+    
+    #[arg(long, default_value = "jsonl")]
+    file_extension: String,
+    
+    /// Number of files to read in parallel (defaults to the number of CPUs)
+    #[arg(short = 'j', long, default_value_t = shuffly::default_concurrency())]
+    concurrency: usize,
+}
+
+#[derive(Args)]
+struct UnshuffleArgs {
+    /// Manifest of the run to undo
+    #[arg(short, long)]
+    manifest: PathBuf,
+    
+    /// Directory to write the rebuilt input files to
+    #[arg(short, long)]
+    output_dir: PathBuf,
+    
+    /// Memory for buffered records in MB
+    #[arg(long, default_value_t = shuffly::DEFAULT_MEMORY_BUDGET_MB)]
+    memory_budget_mb: usize,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Send the library's events to stderr, with progress bars for each phase
+/// when logging text to a terminal.
+fn init_logging(quiet: bool, verbose: u8, format: LogFormat) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let targets = Targets::new().with_target("shuffly", level);
+    
+    match format {
+        LogFormat::Json => {
+            tracing_subscriber::registry()
+                .with(tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr).with_filter(targets))
+                .init();
+        }
+        LogFormat::Text => {
+            // Log lines go through the progress bars' writer so they don't clobber them
+            let progress = IndicatifLayer::new().with_span_field_formatter(hide_indicatif_span_fields(DefaultFields::new()));
+            let log = tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_writer(progress.get_stderr_writer())
+                .with_filter(targets);
+            let progress = (!quiet).then(|| progress.with_filter(IndicatifFilter::new(false)));
+            tracing_subscriber::registry().with(log).with(progress).init();
+        }
+    }
+}
+
+/// Raise the soft limit on open files to the hard limit, so phase 1 can keep
+/// more temp buckets open at once. The library only reads the limit, since
+/// changing it would affect the whole process embedding it.
+#[cfg(unix)]
+fn raise_file_descriptor_limit() {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit/setrlimit only read and write the struct passed in
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 && limit.rlim_cur < limit.rlim_max {
+            let raised = libc::rlimit { rlim_cur: limit.rlim_max, rlim_max: limit.rlim_max };
+            libc::setrlimit(libc::RLIMIT_NOFILE, &raised);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn raise_file_descriptor_limit() {}
+
 fn collect_files_by_extension(dir: &str, extension: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let mut files = Vec::new();
     let dir_path = Path::new(dir);
@@ -46,8 +257,13 @@ fn collect_files_by_extension(dir: &str, extension: &str) -> Result<Vec<PathBuf>
         return Err(format!("'{}' is not a directory", dir).into());
     }
     
-    let target_extension = format!(".{}", extension);
-    let target_extension_gz = format!(".{}.gz", extension);
+    // Plain files plus every compressed variant we know how to decode
+    let mut target_extensions = vec![format!(".{}", extension)];
+    for codec in Compression::ALL {
+        for codec_ext in codec.extensions() {
+            target_extensions.push(format!(".{}.{}", extension, codec_ext));
+        }
+    }
     
     for entry in fs::read_dir(dir_path)? {
         let entry = entry?;
@@ -56,7 +272,7 @@ fn collect_files_by_extension(dir: &str, extension: &str) -> Result<Vec<PathBuf>
         if path.is_file() {
             let path_str = path.to_string_lossy();
             
-            if path_str.ends_with(&target_extension) || path_str.ends_with(&target_extension_gz) {
+            if target_extensions.iter().any(|ext| path_str.ends_with(ext.as_str())) {
                 files.push(path);
             }
         }
@@ -71,30 +287,140 @@ fn collect_files_by_extension(dir: &str, extension: &str) -> Result<Vec<PathBuf>
 }
 
 fn parse_input_files(input_str: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
-    let files: Vec<PathBuf> = input_str
-        .split(':')
-        .map(|s| PathBuf::from(s.trim()))
-        .collect();
-    
-    // Validate all files exist
-    for file in &files {
-        if !file.exists() {
-            return Err(format!("Input file not found: {}", file.display()).into());
+    let (files, repeats) = parse_input_entries(input_str)?;
+    if let Some(file) = repeats.keys().next() {
+        return Err(format!("A repeat multiplier isn't allowed here ({})", file.display()).into());
+    }
+    Ok(files)
+}
+
+/// Input files in order, with the repeat multiplier of each one that has one.
+type InputEntries = (Vec<PathBuf>, BTreeMap<PathBuf, f64>);
+
+/// Parse colon-separated input entries, each a file or glob pattern with an
+/// optional `@MULTIPLIER` (e.g. "books/*.jsonl@3:web.jsonl@0.5:wiki.jsonl").
+/// Returns the files in the order listed and the multipliers other than 1.
+/// A glob's matches are sorted and there must be at least one; a file
+/// matched by several entries is listed once, with the last entry's
+/// multiplier. An existing file is never treated as a glob.
+fn parse_input_entries(input_str: &str) -> Result<InputEntries, Box<dyn std::error::Error>> {
+    let mut files: Vec<(PathBuf, f64)> = Vec::new();
+    for entry in input_str.split(':').map(str::trim) {
+        let (pattern, multiplier) = match entry.rsplit_once('@') {
+            Some((pattern, multiplier)) if multiplier.parse::<f64>().is_ok() => (pattern, multiplier.parse::<f64>()?),
+            _ => (entry, 1.0),
+        };
+        if !(multiplier.is_finite() && multiplier > 0.0) {
+            return Err(format!("Invalid repeat multiplier in '{}'", entry).into());
+        }
+        
+        // A file that exists is taken literally, even if its name has glob
+        // characters such as the brackets in 'data[1].jsonl'
+        let matches = if Path::new(pattern).exists() {
+            vec![PathBuf::from(pattern)]
+        } else if pattern.contains(['*', '?', '[']) {
+            let mut matches = Vec::new();
+            for path in glob::glob(pattern)? {
+                let path = path?;
+                if path.is_file() {
+                    matches.push(path);
+                }
+            }
+            if matches.is_empty() {
+                return Err(format!("No input files match '{}'", pattern).into());
+            }
+            matches.sort();
+            matches
+        } else {
+            return Err(format!("Input file not found: {}", pattern).into());
+        };
+        
+        for file in matches {
+            match files.iter_mut().find(|(listed, _)| *listed == file) {
+                Some(listed) => listed.1 = multiplier,
+                None => files.push((file, multiplier)),
+            }
         }
     }
     
-    Ok(files)
+    let repeats = files.iter().filter(|(_, multiplier)| *multiplier != 1.0).cloned().collect();
+    Ok((files.into_iter().map(|(file, _)| file).collect(), repeats))
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
+fn parse_delimiter(raw: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut delimiter = String::new();
+    let mut chars = raw.chars();
     
-    // Determine input files - parse them here in the CLI layer
-    let input_files = match (cli.input_files, cli.input_dir) {
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            delimiter.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => delimiter.push('\n'),
+            Some('r') => delimiter.push('\r'),
+            Some('t') => delimiter.push('\t'),
+            Some('0') => delimiter.push('\0'),
+            Some('\\') => delimiter.push('\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = Some(&hex)
+                    .filter(|h| h.len() == 2 && h.chars().all(|c| c.is_ascii_hexdigit()))
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .filter(|b| b.is_ascii())
+                    .ok_or_else(|| format!("Invalid escape '\\x{}' in delimiter", hex))?;
+                delimiter.push(byte as char);
+            }
+            Some(other) => return Err(format!("Invalid escape '\\{}' in delimiter", other).into()),
+            None => return Err("Delimiter ends with a lone backslash".into()),
+        }
+    }
+    
+    if delimiter.is_empty() {
+        return Err("Delimiter must not be empty".into());
+    }
+    
+    Ok(delimiter)
+}
+
+/// Parse a `--group` value, expanding directories to the files in them with
+/// `extension`.
+fn parse_group(spec: &str, extension: &str) -> Result<SourceGroup, Box<dyn std::error::Error>> {
+    let mut parts = spec.splitn(3, ':');
+    let (Some(name), Some(weight), Some(paths)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("Group '{}' is not NAME:WEIGHT:PATHS", spec).into());
+    };
+    let weight = weight.trim().parse().map_err(|_| format!("Invalid weight '{}' for group '{}'", weight, name))?;
+    let mut inputs = Vec::new();
+    for path in paths.split(':').map(str::trim) {
+        match Path::new(path).is_dir() {
+            true => inputs.extend(collect_files_by_extension(path, extension)?),
+            false => inputs.extend(parse_input_files(path)?),
+        }
+    }
+    Ok(SourceGroup { name: name.trim().to_string(), weight, inputs })
+}
+
+/// Parse a `--split` value.
+fn parse_split(spec: &str) -> Result<Split, Box<dyn std::error::Error>> {
+    let Some((name, fraction)) = spec.rsplit_once(':') else {
+        return Err(format!("Split '{}' is not NAME:FRACTION", spec).into());
+    };
+    let fraction = fraction.trim().parse().map_err(|_| format!("Invalid fraction '{}' for split '{}'", fraction, name))?;
+    Ok(Split { name: name.trim().to_string(), fraction })
+}
+
+/// Input files from `--input-files` or `--input-dir`, with the repeat
+/// multipliers given in `--input-files`, exiting on error.
+fn input_files_or_exit(
+    input_files: Option<String>,
+    input_dir: Option<String>,
+    extension: &str,
+) -> InputEntries {
+    match (input_files, input_dir) {
         (Some(files_str), None) => {
-            match parse_input_files(&files_str) {
-                Ok(files) => files,
+            match parse_input_entries(&files_str) {
+                Ok(entries) => entries,
                 Err(e) => {
                     eprintln!("Error parsing input files: {}", e);
                     std::process::exit(1);
@@ -102,8 +428,8 @@ async fn main() {
             }
         }
         (None, Some(dir)) => {
-            match collect_files_by_extension(&dir, &cli.file_extension) {
-                Ok(files) => files,
+            match collect_files_by_extension(&dir, extension) {
+                Ok(files) => (files, BTreeMap::new()),
                 Err(e) => {
                     eprintln!("Error reading directory: {}", e);
                     std::process::exit(1);
@@ -118,6 +444,114 @@ async fn main() {
             eprintln!("Error: Cannot specify both --input-files and --input-dir");
             std::process::exit(1);
         }
+    }
+}
+
+async fn verify(args: VerifyArgs, quiet: bool) {
+    let result = match &args.manifest {
+        Some(manifest) => shuffly::verify_manifest(manifest, args.concurrency).await,
+        None => {
+            let (inputs, repeats) = input_files_or_exit(args.input_files, args.input_dir, &args.file_extension);
+            if !repeats.is_empty() {
+                eprintln!("Error: repeat multipliers don't apply to verification");
+                std::process::exit(1);
+            }
+            let outputs = match (args.output_files, args.output_dir) {
+                (Some(files_str), None) => parse_input_files(&files_str),
+                (None, Some(dir)) => collect_files_by_extension(&dir, &args.file_extension),
+                _ => Err("Must specify --manifest, --output-files or --output-dir".into()),
+            };
+            let outputs = outputs.unwrap_or_else(|e| {
+                eprintln!("Error finding output files: {}", e);
+                std::process::exit(1);
+            });
+            let delimiter = parse_delimiter(&args.delimiter).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            shuffly::verify(&inputs, &outputs, &delimiter, args.concurrency).await
+        }
+    };
+    
+    match result {
+        Ok(verification) => {
+            if !quiet {
+                println!("Inputs:  {} ({} blank records skipped)", verification.inputs, verification.blank_records);
+                println!("Outputs: {}", verification.outputs);
+            }
+            for mismatch in &verification.mismatches {
+                println!("Mismatch: {}", mismatch);
+            }
+            if !verification.is_ok() {
+                std::process::exit(1);
+            }
+            if !quiet {
+                println!("OK: the outputs are a permutation of the inputs");
+            }
+        }
+        Err(e) => {
+            eprintln!("Error during verification: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn unshuffle(args: UnshuffleArgs, quiet: bool) {
+    match shuffly::unshuffle(&args.manifest, &args.output_dir, args.memory_budget_mb).await {
+        Ok(_) if quiet => {}
+        Ok(files) => {
+            println!("Rebuilt {} input files:", files.len());
+            for file in &files {
+                println!("  {}", file.display());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error during unshuffling: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut cli = Cli::parse();
+    init_logging(cli.quiet, cli.verbose, cli.log_format);
+    raise_file_descriptor_limit();
+    
+    if let Some(command) = cli.command.take() {
+        match command {
+            Command::Verify(args) => verify(args, cli.quiet).await,
+            Command::Unshuffle(args) => unshuffle(args, cli.quiet).await,
+        }
+        return;
+    }
+    
+    // Determine input files - parse them here in the CLI layer
+    let groups: Vec<SourceGroup> = cli
+        .groups
+        .iter()
+        .map(|spec| parse_group(spec, &cli.file_extension))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("Error parsing groups: {}", e);
+            std::process::exit(1);
+        });
+    let (input_files, repeats) = match groups.is_empty() {
+        true => input_files_or_exit(cli.input_files, cli.input_dir, &cli.file_extension),
+        false => (groups.iter().flat_map(|group| group.inputs.clone()).collect(), BTreeMap::new()),
+    };
+    
+    let splits: Vec<Split> = cli.splits.iter().map(|spec| parse_split(spec)).collect::<Result<_, _>>().unwrap_or_else(|e| {
+        eprintln!("Error parsing splits: {}", e);
+        std::process::exit(1);
+    });
+    
+    let delimiter = match parse_delimiter(&cli.delimiter) {
+        Ok(delimiter) => delimiter,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     };
     
     let config = match ShuffleConfig::new(
@@ -125,23 +559,75 @@ async fn main() {
         &cli.output_dir,
         &cli.output_name,
         cli.max_size_mb,
-        &cli.delimiter,     // Pass delimiter
+        &delimiter,         // Pass delimiter
         &cli.file_extension, // Pass file extension
         cli.seed,
     ) {
-        Ok(config) => config,
+        Ok(mut config) => {
+            config.output_compression = cli.compression;
+            config.output_compression_level = cli.compression_level;
+            config.size_basis = cli.size_basis;
+            config.memory_budget_mb = cli.memory_budget_mb;
+            config.concurrency = cli.concurrency;
+            config.temp_dirs = cli.temp_dirs;
+            config.keep_temp_files = cli.keep_temp;
+            config.provenance = cli.provenance;
+            config.source_groups = groups;
+            config.mixture_records = cli.mixture_records;
+            config.repeats = repeats;
+            config.epochs = cli.epochs;
+            config.splits = splits;
+            config.split_key = cli.split_key;
+            config.group_by = match (cli.group_by, cli.group_by_regex) {
+                (Some(field), _) => Some(GroupKey::Field(field)),
+                (None, Some(pattern)) => Some(GroupKey::Pattern(pattern)),
+                (None, None) => None,
+            };
+            config.shard_mode = match (cli.num_shards, cli.strict_size) {
+                (Some(num_shards), _) => ShardMode::Count(num_shards),
+                (None, true) => ShardMode::MaxSize,
+                (None, false) => ShardMode::PerBucket,
+            };
+            config
+        }
         Err(e) => {
             eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     };
     
-    match shuffly::shuffle_files(&config).await {
-        Ok(output_files) => {
-            println!("Successfully created {} output files:", output_files.len());
-            for file in output_files {
-                println!("  {}", file.display());
+    // Ctrl-C cancels the run, which then removes its temp files
+    let cancel = shuffly::CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                tracing::warn!("Interrupted, cleaning up");
+                cancel.cancel();
+            }
+        }
+    });
+    
+    match shuffly::shuffle_files_with_cancel(&config, &cancel).await {
+        Ok(_) if cli.quiet => {}
+        Ok(report) => {
+            println!("Successfully created {} output files with {} records:", report.outputs.len(), report.records());
+            for output in &report.outputs {
+                let mix: Vec<String> = output
+                    .group_proportions()
+                    .iter()
+                    .map(|(name, proportion)| format!("{} {:.1}%", name, proportion * 100.0))
+                    .collect();
+                match mix.is_empty() {
+                    true => println!("  {}", output.path.display()),
+                    false => println!("  {} ({})", output.path.display(), mix.join(", ")),
+                }
             }
+            println!("Manifest: {}", shuffly::manifest_path(&config).display());
+        }
+        Err(ShuffleError::Cancelled) => {
+            eprintln!("Shuffling interrupted");
+            std::process::exit(130);
         }
         Err(e) => {
             eprintln!("Error during shuffling: {}", e);
@@ -215,6 +701,50 @@ mod tests {
         assert_eq!(result[0], file1);
     }
 
+    #[test]
+    fn test_parse_input_entries_globs_and_multipliers() {
+        let temp_dir = TempDir::new().unwrap();
+        for name in ["a.jsonl", "b.jsonl", "c.txt", "best@home.jsonl", "data[1].txt"] {
+            fs::write(temp_dir.path().join(name), "{}").unwrap();
+        }
+        let path = |name: &str| temp_dir.path().join(name);
+        let dir = temp_dir.path().display();
+        
+        let (files, repeats) = parse_input_entries(&format!("{}/*.jsonl@0.5:{}@3:{}", dir, path("b.jsonl").display(), path("c.txt").display())).unwrap();
+        assert_eq!(files, vec![path("a.jsonl"), path("b.jsonl"), path("best@home.jsonl"), path("c.txt")]);
+        assert_eq!(repeats, BTreeMap::from([(path("a.jsonl"), 0.5), (path("b.jsonl"), 3.0), (path("best@home.jsonl"), 0.5)]));
+        
+        // An '@' that doesn't start a multiplier is part of the path
+        let (files, repeats) = parse_input_entries(&path("best@home.jsonl").display().to_string()).unwrap();
+        assert_eq!((files, repeats), (vec![path("best@home.jsonl")], BTreeMap::new()));
+        
+        // Brackets in an existing file's name aren't a glob
+        let (files, repeats) = parse_input_entries(&format!("{}@2", path("data[1].txt").display())).unwrap();
+        assert_eq!((files, repeats), (vec![path("data[1].txt")], BTreeMap::from([(path("data[1].txt"), 2.0)])));
+        
+        assert!(parse_input_entries(&format!("{}/*.csv", dir)).is_err());
+        assert!(parse_input_entries(&format!("{}@0", path("a.jsonl").display())).is_err());
+        assert!(parse_input_files(&format!("{}@2", path("a.jsonl").display())).is_err());
+    }
+
+    #[test]
+    fn test_parse_delimiter_escapes() {
+        assert_eq!(parse_delimiter("\n").unwrap(), "\n");
+        assert_eq!(parse_delimiter("\\n\\n").unwrap(), "\n\n");
+        assert_eq!(parse_delimiter("\\x1e").unwrap(), "\x1e");
+        assert_eq!(parse_delimiter("<|>").unwrap(), "<|>");
+        assert_eq!(parse_delimiter("a\\\\b").unwrap(), "a\\b");
+    }
+
+    #[test]
+    fn test_parse_delimiter_invalid() {
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter("\\").is_err());
+        assert!(parse_delimiter("\\q").is_err());
+        assert!(parse_delimiter("\\xzz").is_err());
+        assert!(parse_delimiter("\\xff").is_err());
+    }
+
     #[test]
     fn test_collect_files_by_extension_jsonl() {
         let temp_dir = TempDir::new().unwrap();
@@ -224,18 +754,21 @@ mod tests {
         let jsonl_file2 = temp_dir.path().join("test2.jsonl");
         let gz_file = temp_dir.path().join("test3.jsonl.gz");
         let txt_file = temp_dir.path().join("test4.txt");
+        let zst_file = temp_dir.path().join("test5.jsonl.zst");
         
         fs::write(&jsonl_file1, "test content").unwrap();
         fs::write(&jsonl_file2, "test content").unwrap();
         fs::write(&gz_file, "test content").unwrap();
         fs::write(&txt_file, "test content").unwrap();
+        fs::write(&zst_file, "test content").unwrap();
         
         let result = collect_files_by_extension(temp_dir.path().to_str().unwrap(), "jsonl").unwrap();
         
-        assert_eq!(result.len(), 3); // Should include both .jsonl and .jsonl.gz files
+        assert_eq!(result.len(), 4); // Should include .jsonl, .jsonl.gz and .jsonl.zst files
         assert!(result.contains(&jsonl_file1));
         assert!(result.contains(&jsonl_file2));
         assert!(result.contains(&gz_file));
+        assert!(result.contains(&zst_file));
         assert!(!result.iter().any(|p| p == &txt_file));
     }
 
@@ -359,4 +892,67 @@ mod tests {
         assert_eq!(result.len(), 1);
         assert_eq!(result[0], exact_file);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_logging_flags() {
+        let cli = Cli::try_parse_from(["shuffly", "-f", "a.jsonl", "-vv", "--log-format", "json"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+        assert!(cli.log_format == LogFormat::Json);
+        
+        let cli = Cli::try_parse_from(["shuffly", "-f", "a.jsonl"]).unwrap();
+        assert!(!cli.quiet && cli.verbose == 0 && cli.log_format == LogFormat::Text);
+        
+        assert!(Cli::try_parse_from(["shuffly", "-f", "a.jsonl", "--quiet", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_group() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("web");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("b.jsonl"), "{}").unwrap();
+        fs::write(dir.join("a.jsonl.gz"), "").unwrap();
+        let file = temp_dir.path().join("extra.jsonl");
+        fs::write(&file, "{}").unwrap();
+
+        let group = parse_group(&format!("web:0.6:{}:{}", dir.display(), file.display()), "jsonl").unwrap();
+        assert_eq!(group.name, "web");
+        assert_eq!(group.weight, 0.6);
+        assert_eq!(group.inputs, vec![dir.join("a.jsonl.gz"), dir.join("b.jsonl"), file]);
+
+        assert!(parse_group("web:0.6", "jsonl").is_err());
+        assert!(parse_group(&format!("web:lots:{}", dir.display()), "jsonl").is_err());
+        assert!(parse_group("web:1:/nonexistent.jsonl", "jsonl").is_err());
+    }
+
+    #[test]
+    fn test_parse_split() {
+        assert_eq!(parse_split("train: 0.98").unwrap(), Split { name: "train".to_string(), fraction: 0.98 });
+        assert!(parse_split("train").is_err());
+        assert!(parse_split("train:most").is_err());
+        assert!(Cli::try_parse_from(["shuffly", "-f", "a.jsonl", "--split-key", "id"]).is_err());
+    }
+
+    #[test]
+    fn test_group_by_flags_conflict() {
+        let cli = Cli::try_parse_from(["shuffly", "-f", "a.jsonl", "--group-by", "meta.conversation"]).unwrap();
+        assert_eq!(cli.group_by.as_deref(), Some("meta.conversation"));
+        assert!(Cli::try_parse_from(["shuffly", "-f", "a.jsonl", "--group-by", "id", "--group-by-regex", "id=(\\d+)"]).is_err());
+        assert!(Cli::try_parse_from(["shuffly", "--group", "web:1:a.jsonl", "--group-by-regex", "id=(\\d+)"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_subcommand() {
+        let cli = Cli::try_parse_from(["shuffly", "verify", "-m", "out/shuffled.manifest.json", "-q"]).unwrap();
+        assert!(cli.quiet);
+        let Some(Command::Verify(args)) = cli.command else {
+            panic!("expected the verify subcommand");
+        };
+        assert_eq!(args.manifest, Some(PathBuf::from("out/shuffled.manifest.json")));
+        
+        assert!(Cli::try_parse_from(["shuffly", "verify", "-d", "in", "-o", "out"]).is_ok());
+        assert!(Cli::try_parse_from(["shuffly", "verify", "-m", "m.json", "-d", "in"]).is_err());
+        assert!(Cli::try_parse_from(["shuffly", "verify", "-d", "in", "-o", "out", "--output-files", "a.jsonl"]).is_err());
+        assert!(Cli::try_parse_from(["shuffly", "-d", "in", "verify"]).is_err());
+    }
+}
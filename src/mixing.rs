@@ -0,0 +1,405 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+use crate::compression;
+use crate::error::ShuffleError;
+use crate::records::RecordReader;
+use crate::seeding::{self, Permutation};
+use crate::shuffle::ShuffleConfig;
+
+/// Stream identifier for choosing which records of a group get an extra copy
+const SELECTION_STREAM: u64 = 2;
+
+/// Inputs that together should make up a set share of the output, however
+/// many records they hold.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceGroup {
+    /// Names the group in the report
+    pub name: String,
+    /// Target share of the output records, relative to the other groups'
+    pub weight: f64,
+    /// The group's inputs, each of which must also be in `input_files`
+    pub inputs: Vec<PathBuf>,
+}
+
+/// How many times each record is written when mixing source groups or
+/// repeating inputs.
+///
+/// Records are planned in units: each source group, or each input when
+/// repeating. A unit holding `n` records gets `t` output records: its share
+/// of the total by weight, or `n` times its multiplier, rounded. Every record
+/// is written `t / n` times, and `t % n` of them (picked by a seeded
+/// permutation of the unit's records) once more, so a unit is upsampled by
+/// repetition or downsampled by sampling without replacement, and its output
+/// count is exact. Each epoch picks its own records from its own seed.
+#[derive(Debug)]
+pub(crate) struct Mixture {
+    /// Unit of each input, by sorted index
+    input_units: Vec<usize>,
+    /// Records in the earlier inputs of the same unit, by sorted index
+    offsets: Vec<u64>,
+    units: Vec<UnitPlan>,
+    /// Uncompressed bytes the output is expected to hold
+    output_bytes: u64,
+}
+
+#[derive(Debug)]
+struct UnitPlan {
+    records: u64,
+    target: u64,
+    /// Order in which records get an extra copy, by epoch
+    selections: Vec<Permutation>,
+}
+
+/// Whether the run writes records other than once each.
+pub(crate) fn mixes(config: &ShuffleConfig) -> bool {
+    !config.source_groups.is_empty() || !config.repeats.is_empty()
+}
+
+/// Check the source groups and repeats against each other and the inputs.
+pub(crate) fn validate(config: &ShuffleConfig) -> Result<(), ShuffleError> {
+    let invalid = |message: String| Err(ShuffleError::InvalidConfig(message));
+    for (input, &multiplier) in &config.repeats {
+        if !config.input_files.contains(input) {
+            return invalid(format!("{} has a repeat multiplier but is not an input", input.display()));
+        }
+        if !(multiplier.is_finite() && multiplier > 0.0) {
+            return invalid(format!("{} needs a positive repeat multiplier, got {}", input.display(), multiplier));
+        }
+    }
+    if config.source_groups.is_empty() {
+        return match config.mixture_records {
+            Some(_) => invalid("mixture_records needs source_groups".to_string()),
+            None => Ok(()),
+        };
+    }
+    if !config.repeats.is_empty() {
+        return invalid("repeats and source_groups can't be combined; weight the groups instead".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for group in &config.source_groups {
+        if group.name.is_empty() || !names.insert(group.name.as_str()) {
+            return invalid(format!("source group names must be unique and not empty, got '{}'", group.name));
+        }
+        if !(group.weight.is_finite() && group.weight > 0.0) {
+            return invalid(format!("source group '{}' needs a positive weight, got {}", group.name, group.weight));
+        }
+        if group.inputs.is_empty() {
+            return invalid(format!("source group '{}' has no inputs", group.name));
+        }
+    }
+    let inputs: HashSet<&Path> = config.input_files.iter().map(PathBuf::as_path).collect();
+    let mut grouped = HashSet::new();
+    for group in &config.source_groups {
+        for input in &group.inputs {
+            if !inputs.contains(input.as_path()) {
+                return invalid(format!("{} in source group '{}' is not an input", input.display(), group.name));
+            }
+            if !grouped.insert(input.as_path()) {
+                return invalid(format!("{} is in more than one source group", input.display()));
+            }
+        }
+    }
+    match config.input_files.iter().find(|input| !grouped.contains(input.as_path())) {
+        Some(input) => invalid(format!("{} is in no source group", input.display())),
+        None => Ok(()),
+    }
+}
+
+/// Index of the source group of each input, by sorted index; empty unless
+/// the run mixes groups. The config must have been validated.
+pub(crate) fn input_groups(config: &ShuffleConfig) -> Vec<usize> {
+    if config.source_groups.is_empty() {
+        return Vec::new();
+    }
+    let mut sorted_inputs = config.input_files.clone();
+    sorted_inputs.sort();
+    sorted_inputs
+        .iter()
+        .map(|input| {
+            config
+                .source_groups
+                .iter()
+                .position(|group| group.inputs.contains(input))
+                .expect("validated configs put every input in a group")
+        })
+        .collect()
+}
+
+impl Mixture {
+    /// Plan the mixture of a validated config that `mixes` for each of its
+    /// epochs, reading every input once to count its records.
+    pub(crate) async fn new(config: &ShuffleConfig, seed: u64) -> Result<Self, ShuffleError> {
+        let mut sorted_inputs = config.input_files.clone();
+        sorted_inputs.sort();
+        info!(inputs = sorted_inputs.len(), groups = config.source_groups.len(), "Counting records to mix");
+        let counts = count_inputs(&sorted_inputs, &config.delimiter, config.concurrency).await?;
+
+        let (input_units, num_units) = match config.source_groups.len() {
+            0 => ((0..sorted_inputs.len()).collect(), sorted_inputs.len()),
+            groups => (input_groups(config), groups),
+        };
+        let mut unit_counts = vec![(0u64, 0u64); num_units];
+        let mut offsets = Vec::with_capacity(counts.len());
+        for (&unit, &(records, bytes)) in input_units.iter().zip(&counts) {
+            offsets.push(unit_counts[unit].0);
+            unit_counts[unit].0 += records;
+            unit_counts[unit].1 += bytes;
+        }
+        let (names, targets): (Vec<String>, Vec<u64>) = match config.source_groups.len() {
+            0 => sorted_inputs
+                .iter()
+                .zip(&unit_counts)
+                .map(|(input, &(records, _))| {
+                    let multiplier = config.repeats.get(input).copied().unwrap_or(1.0);
+                    (input.display().to_string(), (records as f64 * multiplier).round() as u64)
+                })
+                .unzip(),
+            _ => {
+                let total = config.mixture_records.unwrap_or_else(|| unit_counts.iter().map(|&(records, _)| records).sum());
+                let weights: Vec<f64> = config.source_groups.iter().map(|group| group.weight).collect();
+                (config.source_groups.iter().map(|group| group.name.clone()).collect(), targets(&weights, total))
+            }
+        };
+
+        let selection_seeds: Vec<u64> = (0..config.epochs)
+            .map(|epoch| seeding::derive_seed(seeding::epoch_seed(seed, epoch), &[SELECTION_STREAM]))
+            .collect();
+        let mut units = Vec::new();
+        let mut output_bytes = 0;
+        for (index, (name, (target, &(records, bytes)))) in names.iter().zip(targets.into_iter().zip(&unit_counts)).enumerate() {
+            if records == 0 && target > 0 {
+                return Err(ShuffleError::InvalidConfig(format!("source group '{}' has no records", name)));
+            }
+            if records > 0 && target == 0 && config.source_groups.is_empty() {
+                let message = format!("the repeat multiplier of '{}' rounds its {} records down to none", name, records);
+                return Err(ShuffleError::InvalidConfig(message));
+            }
+            if target != records {
+                info!(
+                    source = %name,
+                    records,
+                    target,
+                    factor = format_args!("{:.3}", target as f64 / records.max(1) as f64),
+                    "Resampling"
+                );
+            }
+            output_bytes += (bytes as u128 * target as u128 / records.max(1) as u128) as u64;
+            units.push(UnitPlan {
+                records,
+                target,
+                selections: selection_seeds
+                    .iter()
+                    .map(|&selection_seed| Permutation::new(seeding::derive_seed(selection_seed, &[index as u64]), records))
+                    .collect(),
+            });
+        }
+        Ok(Self { input_units, offsets, units, output_bytes })
+    }
+
+    /// Copies to write in `epoch` of record `index` (counting only non-blank
+    /// records) of the input with sorted index `source`.
+    pub(crate) fn copies(&self, epoch: usize, source: usize, index: u64) -> u32 {
+        let unit = &self.units[self.input_units[source]];
+        let index = self.offsets[source] + index;
+        let extra = index < unit.records && unit.selections[epoch].apply(index) < unit.target % unit.records;
+        (unit.target / unit.records) as u32 + u32::from(extra)
+    }
+
+    /// Uncompressed bytes each epoch's output is expected to hold.
+    pub(crate) fn output_bytes(&self) -> u64 {
+        self.output_bytes
+    }
+}
+
+/// Split `total` records in proportion to `weights`, rounding by largest
+/// remainder so the parts add up exactly (ties go to the earlier group).
+pub(crate) fn targets(weights: &[f64], total: u64) -> Vec<u64> {
+    let sum: f64 = weights.iter().sum();
+    let exact: Vec<f64> = weights.iter().map(|weight| weight / sum * total as f64).collect();
+    let mut parts: Vec<u64> = exact.iter().map(|share| share.floor() as u64).collect();
+    let mut by_remainder: Vec<usize> = (0..weights.len()).collect();
+    by_remainder.sort_by(|&a, &b| (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor())));
+    let assigned: u64 = parts.iter().sum();
+    for &index in by_remainder.iter().cycle().take(total.saturating_sub(assigned) as usize) {
+        parts[index] += 1;
+    }
+    parts
+}
+
+/// Non-blank records and uncompressed bytes (counting a delimiter after
+/// every record, blank or not) of each input, reading up to `concurrency`
+/// at once.
+async fn count_inputs(paths: &[PathBuf], delimiter: &str, concurrency: usize) -> Result<Vec<(u64, u64)>, ShuffleError> {
+    let paths = Arc::new(paths.to_vec());
+    let next = Arc::new(AtomicUsize::new(0));
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency.min(paths.len()) {
+        let (paths, next, delimiter) = (paths.clone(), next.clone(), delimiter.to_string());
+        workers.spawn(async move {
+            let mut counts = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                counts.push((index, count_input(path, &delimiter).await?));
+            }
+            Ok::<_, ShuffleError>(counts)
+        });
+    }
+
+    let mut counts = vec![(0, 0); paths.len()];
+    while let Some(result) = workers.join_next().await {
+        for (index, count) in result.map_err(io::Error::other)?? {
+            counts[index] = count;
+        }
+    }
+    Ok(counts)
+}
+
+async fn count_input(path: &Path, delimiter: &str) -> Result<(u64, u64), ShuffleError> {
+    debug!(path = %path.display(), "Counting records");
+    let reader = compression::open_input(path).await.map_err(|e| ShuffleError::opening_input(path, e))?;
+    let mut records = RecordReader::new(reader, delimiter);
+    let (mut ordinal, mut non_blank, mut bytes) = (0, 0, 0);
+    while let Some(line) = records.next_record().await.map_err(|e| ShuffleError::reading_input(path, ordinal, e))? {
+        bytes += (line.len() + delimiter.len()) as u64;
+        non_blank += u64::from(!line.trim().is_empty());
+        ordinal += 1;
+    }
+    Ok((non_blank, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::shuffle::{shuffle_files, ShardMode};
+
+    #[test]
+    fn test_targets_add_up() {
+        assert_eq!(targets(&[0.6, 0.3, 0.1], 1000), vec![600, 300, 100]);
+        assert_eq!(targets(&[1.0, 1.0, 1.0], 10), vec![4, 3, 3]);
+        assert_eq!(targets(&[2.0, 1.0], 0), vec![0, 0]);
+        let parts = targets(&[0.123, 0.456, 0.789], 12_345);
+        assert_eq!(parts.iter().sum::<u64>(), 12_345);
+    }
+
+    #[tokio::test]
+    async fn test_mixes_groups_in_proportion() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut groups = Vec::new();
+        for (name, records, weight) in [("web", 1000, 0.6), ("code", 200, 0.3), ("books", 50, 0.1)] {
+            let path = temp_dir.path().join(format!("{}.jsonl", name));
+            let content: String = (0..records).map(|i| format!("{{\"{}\": {}}}\n", name, i)).collect();
+            fs::write(&path, content + "\n").unwrap();
+            groups.push(SourceGroup { name: name.to_string(), weight, inputs: vec![path] });
+        }
+        let inputs = groups.iter().flat_map(|group| group.inputs.clone()).collect();
+        let out_dir = temp_dir.path().join("out");
+        let mut config = ShuffleConfig::new(inputs, out_dir.to_str().unwrap(), "mixed", 64, "\n", "jsonl", Some(9)).unwrap();
+        config.shard_mode = ShardMode::Count(4);
+        config.source_groups = groups;
+        config.mixture_records = Some(1000);
+        let report = shuffle_files(&config).await.unwrap();
+
+        let summary: Vec<(&str, u64, u64)> =
+            report.groups.iter().map(|group| (group.name.as_str(), group.input_records, group.records)).collect();
+        assert_eq!(summary, vec![("web", 1000, 600), ("code", 200, 300), ("books", 50, 100)]);
+        assert_eq!(report.records(), 1000);
+
+        // Downsampled records appear at most once, upsampled ones evenly often
+        let mut seen: HashMap<String, u64> = HashMap::new();
+        for output in &report.outputs {
+            assert_eq!(output.group_records.values().sum::<u64>(), output.records);
+            let content = fs::read_to_string(&output.path).unwrap();
+            for line in content.lines() {
+                *seen.entry(line.to_string()).or_default() += 1;
+            }
+            let web = content.lines().filter(|line| line.contains("web")).count() as u64;
+            assert_eq!(output.group_records["web"], web);
+        }
+        let copies = |group: &str| -> Vec<u64> {
+            let mut copies: Vec<u64> = seen.iter().filter(|(line, _)| line.contains(group)).map(|(_, &n)| n).collect();
+            copies.sort();
+            copies.dedup();
+            copies
+        };
+        assert_eq!(copies("web"), vec![1]);
+        assert_eq!(copies("code"), vec![1, 2]);
+        assert_eq!(copies("books"), vec![2]);
+
+        // A group without inputs of its own is refused
+        config.source_groups[2].inputs.clear();
+        assert!(matches!(shuffle_files(&config).await, Err(ShuffleError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_repeats_spread_copies_evenly() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut inputs = Vec::new();
+        for (name, records) in [("rare", 10), ("common", 100), ("plain", 20)] {
+            let path = temp_dir.path().join(format!("{}.jsonl", name));
+            let content: String = (0..records).map(|i| format!("{{\"{}\": {}}}\n", name, i)).collect();
+            fs::write(&path, content).unwrap();
+            inputs.push(path);
+        }
+        let out_dir = temp_dir.path().join("out");
+        let mut config = ShuffleConfig::new(inputs.clone(), out_dir.to_str().unwrap(), "repeated", 64, "\n", "jsonl", Some(5)).unwrap();
+        config.repeats = BTreeMap::from([(inputs[0].clone(), 3.0), (inputs[1].clone(), 0.25)]);
+        let report = shuffle_files(&config).await.unwrap();
+
+        let output_records: Vec<Option<u64>> = report.inputs.iter().map(|input| input.output_records).collect();
+        assert_eq!(output_records, vec![Some(25), Some(20), Some(30)]);
+        assert_eq!(report.records(), 75);
+
+        let mut seen: HashMap<String, u64> = HashMap::new();
+        for output in &report.outputs {
+            for line in fs::read_to_string(&output.path).unwrap().lines() {
+                *seen.entry(line.to_string()).or_default() += 1;
+            }
+        }
+        assert!(seen.iter().filter(|(line, _)| line.contains("rare")).all(|(_, &n)| n == 3));
+        assert!(seen.iter().filter(|(line, _)| !line.contains("rare")).all(|(_, &n)| n == 1));
+
+        // Copies of a record are spread through the output, not adjacent
+        let lines: Vec<String> = report.outputs.iter().flat_map(|output| {
+            fs::read_to_string(&output.path).unwrap().lines().map(String::from).collect::<Vec<_>>()
+        }).collect();
+        let spans: Vec<usize> = seen
+            .keys()
+            .filter(|line| line.contains("rare"))
+            .map(|line| {
+                let positions: Vec<usize> = (0..lines.len()).filter(|&i| lines[i] == *line).collect();
+                positions[2] - positions[0]
+            })
+            .collect();
+        let mean_span = spans.iter().sum::<usize>() as f64 / spans.len() as f64;
+        assert!(mean_span > 20.0, "copies are {} records apart on average", mean_span);
+
+        // output_records counts one epoch, not all of them
+        let mut epochs = config.clone();
+        epochs.output_dir = temp_dir.path().join("epochs");
+        epochs.epochs = 2;
+        let report = shuffle_files(&epochs).await.unwrap();
+        let output_records: Vec<Option<u64>> = report.inputs.iter().map(|input| input.output_records).collect();
+        assert_eq!(output_records, vec![Some(25), Some(20), Some(30)]);
+        assert_eq!(report.records(), 150);
+
+        config.repeats.insert(inputs[0].clone(), 0.01);
+        assert!(matches!(shuffle_files(&config).await, Err(ShuffleError::InvalidConfig(_))));
+
+        config.repeats.insert(inputs[0].clone(), 3.0);
+        config.repeats.insert(temp_dir.path().join("missing.jsonl"), 2.0);
+        assert!(matches!(shuffle_files(&config).await, Err(ShuffleError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,389 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use crate::bucket::TempRecord;
+use crate::compression::{self, Compression};
+use crate::error::{IoContext, ShuffleError};
+use crate::mixing;
+use crate::shuffle::{ShuffleConfig, SizeBasis};
+
+/// Allowance for codec framing and trailers when bounding compressed shards.
+const COMPRESSED_SLACK_BYTES: u64 = 1024;
+
+/// How the shuffled record stream is cut into output shards.
+pub(crate) enum ShardPlan {
+    /// Everything goes to a single file
+    Single(PathBuf),
+    /// Everything goes to a single uncompressed scratch file
+    Scratch(PathBuf),
+    /// Exactly one shard per entry, each holding that many records
+    Counts(Vec<usize>),
+    /// As many shards as needed, none larger than this many bytes
+    MaxBytes(u64),
+}
+
+impl ShardPlan {
+    /// Path of shard `index` under this plan.
+    pub(crate) fn shard_path(&self, config: &ShuffleConfig, index: usize) -> PathBuf {
+        match self {
+            ShardPlan::Single(path) | ShardPlan::Scratch(path) => path.clone(),
+            ShardPlan::Counts(counts) => output_path(config, index, counts.len() > 1),
+            ShardPlan::MaxBytes(_) => output_path(config, index, true),
+        }
+    }
+}
+
+/// What a finished shard holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ShardDigest {
+    pub(crate) records: usize,
+    /// Size of the file on disk
+    pub(crate) bytes: u64,
+    /// Hex SHA-256 of the file on disk; empty for scratch files
+    pub(crate) sha256: String,
+    /// Records from each source group, when mixing
+    pub(crate) groups: Vec<u64>,
+}
+
+/// Writes a stream of records into output shards according to a `ShardPlan`.
+pub(crate) struct ShardWriter<'a> {
+    config: &'a ShuffleConfig,
+    plan: ShardPlan,
+    shard: Option<OpenShard>,
+    next_index: usize,
+    finished: Vec<(PathBuf, ShardDigest)>,
+    /// Source group of each input when mixing, by sorted index
+    input_groups: Vec<usize>,
+}
+
+struct OpenShard {
+    path: PathBuf,
+    /// Where the shard is written until it is complete and renamed to
+    /// `path`; `None` once renamed, or for scratch files written in place
+    partial: Option<PathBuf>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    records: usize,
+    /// Records from each source group, when mixing
+    groups: Vec<u64>,
+    /// Uncompressed bytes accepted so far
+    bytes: u64,
+    /// Uncompressed bytes accepted since the encoder was last flushed
+    pending: u64,
+    /// Bytes that actually reached the file
+    on_disk: Arc<FileTally>,
+    /// Where each record came from, when `config.provenance` is set (and
+    /// for scratch files when mixing)
+    provenance: Option<Sidecar>,
+}
+
+/// A shard's provenance sidecar: one `source<TAB>ordinal` line per record
+/// of the shard, in the same order, where `source` indexes the inputs
+/// sorted by path and `ordinal` is the record's position in that input.
+struct Sidecar {
+    path: PathBuf,
+    /// As for `OpenShard::partial`
+    partial: Option<PathBuf>,
+    writer: BufWriter<File>,
+}
+
+impl<'a> ShardWriter<'a> {
+    pub(crate) fn new(config: &'a ShuffleConfig, plan: ShardPlan) -> Self {
+        Self {
+            config,
+            plan,
+            shard: None,
+            next_index: 0,
+            finished: Vec::new(),
+            input_groups: mixing::input_groups(config),
+        }
+    }
+
+    /// Continue a plan whose first shards were already written by an
+    /// interrupted run; the next record goes to a new shard after them.
+    pub(crate) fn resume(config: &'a ShuffleConfig, plan: ShardPlan, finished: Vec<(PathBuf, ShardDigest)>) -> Self {
+        Self {
+            next_index: finished.len(),
+            finished,
+            ..Self::new(config, plan)
+        }
+    }
+
+    /// Shards closed so far.
+    pub(crate) fn finished(&self) -> &[(PathBuf, ShardDigest)] {
+        &self.finished
+    }
+
+    /// Append one record to the current shard, noting its origin in the
+    /// shard's sidecar if provenance is recorded. Only the record's line is
+    /// written, followed by the delimiter.
+    pub(crate) async fn write_record(&mut self, record: &TempRecord) -> Result<(), ShuffleError> {
+        let origin = (record.source, record.ordinal);
+        let record = record.line.as_str();
+        let record_bytes = (record.len() + self.config.delimiter.len()) as u64;
+
+        match self.plan {
+            ShardPlan::Single(_) | ShardPlan::Scratch(_) => {}
+            ShardPlan::Counts(ref counts) => {
+                let planned = counts.len();
+                // Skip over shards that are already full (or meant to stay empty)
+                while self.current_shard_full() {
+                    self.close_shard().await?;
+                    if self.next_index >= planned {
+                        return Err(io::Error::other("more records than planned for the output shards").into());
+                    }
+                    self.open_shard().await?;
+                }
+            }
+            ShardPlan::MaxBytes(limit) => {
+                if record_bytes + self.slack(record_bytes) > limit {
+                    return Err(ShuffleError::RecordTooLarge { bytes: record_bytes, limit });
+                }
+                if self.would_exceed(record_bytes, limit).await? {
+                    self.close_shard().await?;
+                }
+            }
+        }
+
+        if self.shard.is_none() {
+            self.open_shard().await?;
+        }
+        let shard = self.shard.as_mut().unwrap();
+        shard.writer.write_all(record.as_bytes()).await.at(&shard.path)?;
+        shard.writer.write_all(self.config.delimiter.as_bytes()).await.at(&shard.path)?;
+        if let Some(sidecar) = shard.provenance.as_mut() {
+            let line = format!("{}\t{}\n", origin.0, origin.1);
+            sidecar.writer.write_all(line.as_bytes()).await.at(&sidecar.path)?;
+        }
+        if let Some(&group) = self.input_groups.get(origin.0 as usize) {
+            shard.groups[group] += 1;
+        }
+        shard.records += 1;
+        shard.bytes += record_bytes;
+        shard.pending += record_bytes;
+        Ok(())
+    }
+
+    /// Close the last shard (creating any planned shards that stayed empty)
+    /// and return every shard written. `Single` and `Scratch` plans that
+    /// received no records create no file.
+    pub(crate) async fn finish(mut self) -> Result<Vec<(PathBuf, ShardDigest)>, ShuffleError> {
+        if let ShardPlan::Counts(ref counts) = self.plan {
+            let planned = counts.len();
+            while self.next_index < planned {
+                self.close_shard().await?;
+                self.open_shard().await?;
+            }
+        }
+        self.close_shard().await?;
+        Ok(self.finished)
+    }
+
+    /// With a `Counts` plan, whether the open shard holds its quota (or there is none).
+    fn current_shard_full(&self) -> bool {
+        match (&self.plan, &self.shard) {
+            (ShardPlan::Counts(counts), Some(shard)) => shard.records >= counts[self.next_index - 1],
+            _ => true,
+        }
+    }
+
+    fn counts_compressed(&self) -> bool {
+        self.config.size_basis == SizeBasis::Compressed && self.config.output_compression != Compression::None
+    }
+
+    /// Worst-case framing overhead for `bytes` of data in a compressed shard.
+    fn slack(&self, bytes: u64) -> u64 {
+        if self.counts_compressed() {
+            COMPRESSED_SLACK_BYTES + bytes / 64
+        } else {
+            0
+        }
+    }
+
+    /// Whether appending `record_bytes` could push the open shard past `limit`.
+    ///
+    /// For compressed sizing the encoder is flushed when the pessimistic
+    /// estimate gets close, so the decision is made on bytes actually on disk.
+    async fn would_exceed(&mut self, record_bytes: u64, limit: u64) -> Result<bool, ShuffleError> {
+        let compressed = self.counts_compressed();
+        let shard = match self.shard.as_mut() {
+            Some(shard) if shard.records > 0 => shard,
+            _ => return Ok(false),
+        };
+        if !compressed {
+            return Ok(shard.bytes + record_bytes > limit);
+        }
+
+        let estimate = |shard: &OpenShard| {
+            let unflushed = shard.pending + record_bytes;
+            shard.on_disk.bytes.load(Ordering::Relaxed) + unflushed + COMPRESSED_SLACK_BYTES + unflushed / 64
+        };
+        if estimate(shard) <= limit {
+            return Ok(false);
+        }
+        shard.writer.flush().await.at(&shard.path)?;
+        shard.pending = 0;
+        Ok(estimate(shard) > limit)
+    }
+
+    async fn open_shard(&mut self) -> Result<(), ShuffleError> {
+        let (compression, level) = match self.plan {
+            ShardPlan::Scratch(_) => (Compression::None, None),
+            _ => (self.config.output_compression, self.config.output_compression_level),
+        };
+        let path = self.plan.shard_path(self.config, self.next_index);
+        self.next_index += 1;
+
+        // Consumers should never see a half-written shard
+        let partial = match self.plan {
+            ShardPlan::Scratch(_) => None,
+            _ => Some(partial_path(&path)),
+        };
+        // Scratch files are read back straight away, so aren't worth hashing
+        let on_disk = Arc::new(FileTally {
+            bytes: AtomicU64::new(0),
+            sha256: partial.is_some().then(|| Mutex::new(Sha256::new())),
+        });
+        let file = TallyWriter {
+            inner: File::create(partial.as_ref().unwrap_or(&path)).await.at(&path)?,
+            tally: on_disk.clone(),
+        };
+        let writer = compression::output_writer(file, compression, level);
+        let provenance = match self.config.provenance || matches!(self.plan, ShardPlan::Scratch(_)) && scratch_sidecars(self.config) {
+            true => {
+                let sidecar_path = provenance_path(&path);
+                let sidecar_partial = partial.as_ref().map(|_| partial_path(&sidecar_path));
+                let file = File::create(sidecar_partial.as_ref().unwrap_or(&sidecar_path)).await.at(&sidecar_path)?;
+                Some(Sidecar { path: sidecar_path, partial: sidecar_partial, writer: BufWriter::new(file) })
+            }
+            false => None,
+        };
+        self.shard = Some(OpenShard {
+            path,
+            partial,
+            writer,
+            records: 0,
+            groups: vec![0; self.config.source_groups.len()],
+            bytes: 0,
+            pending: 0,
+            on_disk,
+            provenance,
+        });
+        Ok(())
+    }
+
+    async fn close_shard(&mut self) -> Result<(), ShuffleError> {
+        if let Some(mut shard) = self.shard.take() {
+            shard.writer.shutdown().await.at(&shard.path)?;
+            // The sidecar is in place before its shard, so a shard never lacks one
+            if let Some(sidecar) = shard.provenance.as_mut() {
+                sidecar.writer.shutdown().await.at(&sidecar.path)?;
+                if let Some(partial) = sidecar.partial.take() {
+                    tokio::fs::rename(&partial, &sidecar.path).await.at(&sidecar.path)?;
+                }
+            }
+            if let Some(partial) = shard.partial.take() {
+                tokio::fs::rename(&partial, &shard.path).await.at(&shard.path)?;
+            }
+            let digest = ShardDigest {
+                records: shard.records,
+                bytes: shard.on_disk.bytes.load(Ordering::Relaxed),
+                sha256: shard.on_disk.sha256.as_ref().map_or_else(String::new, |sha256| {
+                    format!("{:x}", sha256.lock().unwrap().clone().finalize())
+                }),
+                groups: std::mem::take(&mut shard.groups),
+            };
+            self.finished.push((shard.path.clone(), digest));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpenShard {
+    fn drop(&mut self) {
+        // Abandoned before completion (an error or a cancelled run)
+        if let Some(partial) = &self.partial {
+            let _ = std::fs::remove_file(partial);
+        }
+        if let Some(partial) = self.provenance.as_ref().and_then(|sidecar| sidecar.partial.as_ref()) {
+            let _ = std::fs::remove_file(partial);
+        }
+    }
+}
+
+/// Where a shard is written before being renamed into place.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+/// Where the provenance sidecar of the shard (or scratch file) at `path` goes.
+pub(crate) fn provenance_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".provenance");
+    path.with_file_name(name)
+}
+
+/// Whether scratch files get a provenance sidecar: when shards record
+/// provenance, and when mixing, as the shards count records per group.
+pub(crate) fn scratch_sidecars(config: &ShuffleConfig) -> bool {
+    config.provenance || !config.source_groups.is_empty()
+}
+
+/// File name extension for output shards, including the codec suffix.
+pub(crate) fn output_extension(config: &ShuffleConfig) -> String {
+    match config.output_compression.extension() {
+        Some(codec_ext) => format!("{}.{}", config.file_extension, codec_ext),
+        None => config.file_extension.clone(),
+    }
+}
+
+/// Path of output shard `index`; unnumbered when it is the only shard.
+pub(crate) fn output_path(config: &ShuffleConfig, index: usize, numbered: bool) -> PathBuf {
+    let output_filename = if numbered {
+        format!("{:04}_{}.{}", config.output_name, index + 1, output_extension(config))
+    } else {
+        format!("{}.{}", config.output_name, output_extension(config))
+    };
+    config.output_dir.join(output_filename)
+}
+
+/// Bytes that reached a file, and optionally their hash.
+struct FileTally {
+    bytes: AtomicU64,
+    sha256: Option<Mutex<Sha256>>,
+}
+
+/// Tallies bytes that reach the underlying writer.
+struct TallyWriter<W> {
+    inner: W,
+    tally: Arc<FileTally>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TallyWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.tally.bytes.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(sha256) = &self.tally.sha256 {
+                sha256.lock().unwrap().update(&buf[..n]);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use tracing_indicatif::style::ProgressStyle;
+use crate::error::Phase;
+use crate::shuffle::ShuffleConfig;
+
+/// Least time between two reports to a `ProgressObserver` within a phase.
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How far a phase has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// `Phase::Distribute` or `Phase::Shuffle`
+    pub phase: Phase,
+    /// Uncompressed input bytes read (phase 1) or temp bucket bytes
+    /// shuffled (phase 2)
+    pub bytes: u64,
+    /// What `bytes` will reach, if known; only an estimate in phase 1, and
+    /// unknown when resuming phase 1
+    pub total_bytes: Option<u64>,
+    /// Records scattered (phase 1) or written to output shards (phase 2)
+    pub records: u64,
+    /// What `records` will reach, if known (only in phase 2)
+    pub total_records: Option<u64>,
+    /// The input (phase 1) or temp bucket (phase 2) most recently worked on
+    pub current_file: Option<PathBuf>,
+}
+
+/// Receives progress reports while `shuffle_files` runs: when each phase
+/// starts, at most every half second while it runs, and when it ends.
+///
+/// Reports come from the task driving the run, so a slow observer slows
+/// the shuffle down.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> ProgressObserver for F {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+impl fmt::Debug for dyn ProgressObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressObserver")
+    }
+}
+
+/// Tracks one phase's progress, drawing it on the phase's span and passing
+/// it on to the config's observer.
+///
+/// Progress bars are drawn by `tracing_indicatif::IndicatifLayer` when the
+/// application installs it, as the CLI does; otherwise they cost nothing.
+pub(crate) struct Reporter {
+    span: Span,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    progress: Progress,
+    last_report: Instant,
+}
+
+impl Reporter {
+    /// Phase 1, whose bar counts input bytes against the estimate (when
+    /// there is one; a resumed run has none).
+    pub(crate) fn distribute(config: &ShuffleConfig, estimated_input_size: Option<usize>) -> Self {
+        let span = info_span!("distribute", "indicatif.pb_show" = Empty);
+        match estimated_input_size {
+            Some(total) => {
+                span.pb_set_style(&style(
+                    "{spinner} phase 1 [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta}) {msg}",
+                ));
+                span.pb_set_length(total as u64);
+            }
+            None => span.pb_set_style(&style("{spinner} phase 1 {bytes} ({bytes_per_sec}) {msg}")),
+        }
+        Self::new(config, span, Phase::Distribute, estimated_input_size.map(|total| total as u64), None)
+    }
+
+    /// Phase 2, whose bar counts records written.
+    pub(crate) fn shuffle(config: &ShuffleConfig, total_bytes: u64, total_records: usize) -> Self {
+        let span = info_span!("shuffle", "indicatif.pb_show" = Empty);
+        span.pb_set_style(&style("{spinner} phase 2 [{bar:30}] {human_pos}/{human_len} records (ETA {eta})"));
+        span.pb_set_length(total_records as u64);
+        Self::new(config, span, Phase::Shuffle, Some(total_bytes), Some(total_records as u64))
+    }
+
+    fn new(
+        config: &ShuffleConfig,
+        span: Span,
+        phase: Phase,
+        total_bytes: Option<u64>,
+        total_records: Option<u64>,
+    ) -> Self {
+        let reporter = Self {
+            span,
+            observer: config.progress.clone(),
+            progress: Progress {
+                phase,
+                bytes: 0,
+                total_bytes,
+                records: 0,
+                total_records,
+                current_file: None,
+            },
+            last_report: Instant::now(),
+        };
+        reporter.report();
+        reporter
+    }
+
+    /// The span the phase runs in.
+    pub(crate) fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    /// Count `bytes` and `records` more, processed from `file`.
+    pub(crate) fn advance(&mut self, bytes: u64, records: u64, file: &Path) {
+        self.progress.bytes += bytes;
+        self.progress.records += records;
+        if self.progress.current_file.as_deref() != Some(file) {
+            self.progress.current_file = Some(file.to_path_buf());
+        }
+        match self.progress.phase {
+            Phase::Shuffle => self.span.pb_set_position(self.progress.records),
+            _ => {
+                self.span.pb_set_position(self.progress.bytes);
+                self.span.pb_set_message(&format!("{} records", self.progress.records));
+            }
+        }
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.report();
+            self.last_report = Instant::now();
+        }
+    }
+
+    /// Report the phase's final state.
+    pub(crate) fn finish(&self) {
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(observer) = &self.observer {
+            observer.on_progress(&self.progress);
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress bar templates are valid")
+        .progress_chars("=> ")
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tracing::{debug, info};
+use crate::bucket::{self, BucketWriters, TempRecord};
+use crate::compression::{self, Compression};
+use crate::error::{IoContext, ShuffleError};
+use crate::output;
+use crate::records::RecordReader;
+use crate::report::{OutputStats, ShuffleReport};
+use crate::shuffle::{self, MAX_OPEN_OUTPUT_FILES};
+
+/// Reads the origins listed in a provenance sidecar, in order.
+pub(crate) struct SidecarReader {
+    lines: Lines<BufReader<File>>,
+}
+
+impl SidecarReader {
+    pub(crate) async fn open(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self { lines: BufReader::new(File::open(path).await?).lines() })
+    }
+
+    /// The next record's input index and ordinal, or `None` at the end.
+    pub(crate) async fn next_origin(&mut self) -> Result<Option<(u32, u64)>, io::Error> {
+        let Some(line) = self.lines.next_line().await? else {
+            return Ok(None);
+        };
+        line.split_once('\t')
+            .and_then(|(source, ordinal)| Some((source.parse().ok()?, ordinal.parse().ok()?)))
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad sidecar line '{}'", line)))
+    }
+}
+
+/// Rebuild the inputs of a run made with `ShuffleConfig::provenance` from
+/// its output shards and their sidecars, as listed in the run's manifest.
+/// Returns the rebuilt files in the manifest's input order.
+///
+/// Each input is written to `output_dir` under its own file name (later
+/// inputs sharing a name get a `1_`, `2_`, ... prefix) and compressed with
+/// the codec that name implies. Records come back in their original order,
+/// each followed by the delimiter; blank records, which the shuffle dropped,
+/// come back empty. Records are sorted back into place through temp buckets
+/// in `output_dir`, holding about `memory_budget_mb` of them in memory.
+/// Every epoch holds all the records, so only the first epoch's shards are read.
+pub async fn unshuffle(manifest: &Path, output_dir: &Path, memory_budget_mb: usize) -> Result<Vec<PathBuf>, ShuffleError> {
+    if memory_budget_mb == 0 {
+        return Err(ShuffleError::InvalidConfig("memory budget must be at least 1 MB".to_string()));
+    }
+    let report = ShuffleReport::read(manifest).await?;
+    if !report.config.source_groups.is_empty() || !report.config.repeats.is_empty() {
+        return Err(ShuffleError::InvalidConfig(
+            "a mixed or repeated run repeats and drops records, so it can't be unshuffled".to_string(),
+        ));
+    }
+    let outputs: Vec<&OutputStats> = report.outputs.iter().filter(|output| output.epoch.unwrap_or(0) == 0).collect();
+    if let Some(output) = outputs.iter().find(|output| output.provenance.is_none()) {
+        return Err(ShuffleError::InvalidConfig(format!(
+            "{} has no provenance sidecar; unshuffling needs a run made with provenance recorded",
+            output.path.display()
+        )));
+    }
+    tokio::fs::create_dir_all(output_dir).await.at(output_dir)?;
+
+    // Every input ordinal, blank or not, gets its own key, so key order is
+    // input order and buckets hold consecutive stretches of it
+    let spans: Vec<u64> = report.inputs.iter().map(|input| input.records + input.blank_records).collect();
+    let offsets: Vec<u64> = spans.iter().scan(0, |offset, span| Some(std::mem::replace(offset, *offset + span))).collect();
+    let total_keys = spans.iter().sum::<u64>().max(1);
+    let half_budget = memory_budget_mb * 1024 * 1024 / 2;
+    let input_bytes: u64 = report.inputs.iter().map(|input| input.bytes).sum();
+    let num_buckets = input_bytes.div_ceil(half_budget as u64).max(1) as usize;
+    info!(inputs = report.inputs.len(), shards = outputs.len(), temp_files = num_buckets, "Unshuffling");
+
+    let buffer_size = shuffle::bucket_buffer_size(half_budget, num_buckets)?;
+
+    let temp_files: Vec<PathBuf> = (0..num_buckets)
+        .map(|i| output_dir.join(format!(".{}_unshuffle_temp_{:04}", report.config.output_name, i)))
+        .collect();
+    let _cleanup = RemoveOnDrop(temp_files.clone());
+    for temp_file in &temp_files {
+        File::create(temp_file).await.at(temp_file)?;
+    }
+
+    let max_open = bucket::max_open_buckets(MAX_OPEN_OUTPUT_FILES);
+    let mut writers = BucketWriters::new(temp_files.clone(), buffer_size, max_open);
+    for output in outputs {
+        let shard = &output.path;
+        let sidecar = output.provenance.as_deref().unwrap();
+        debug!(shard = %shard.display(), "Scattering shard");
+        let reader = compression::open_input(shard).await.map_err(|e| ShuffleError::opening_input(shard, e))?;
+        let mut records = RecordReader::new(reader, &report.config.delimiter);
+        let mut origins = SidecarReader::open(sidecar).await.map_err(|e| ShuffleError::opening_input(sidecar, e))?;
+        let mut read = 0;
+        loop {
+            let line = records.next_record().await.map_err(|e| ShuffleError::reading_input(shard, read, e))?;
+            let origin = origins.next_origin().await.map_err(|e| ShuffleError::reading_input(sidecar, read, e))?;
+            let (line, (source, ordinal)) = match (line, origin) {
+                (Some(line), Some(origin)) => (line, origin),
+                (None, None) => break,
+                _ => return Err(malformed(sidecar, read, "sidecar and shard hold different numbers of records")),
+            };
+            if spans.get(source as usize).is_none_or(|&span| ordinal >= span) {
+                return Err(malformed(sidecar, read, "sidecar names a record the manifest's inputs don't have"));
+            }
+            let key = offsets[source as usize] + ordinal;
+            let bucket = (key as u128 * num_buckets as u128 / total_keys as u128) as usize;
+            writers.push(bucket, &TempRecord { key, source, ordinal, copy: 0, line }).await?;
+            read += 1;
+        }
+    }
+    writers.checkpoint().await?;
+    drop(writers);
+
+    let mut rebuilt = Rebuilt::new(&report, output_dir);
+    for temp_file in &temp_files {
+        let mut records = Vec::new();
+        let mut reader = bucket::open_bucket(temp_file).await.at(temp_file)?;
+        while let Some(record) = reader.next_record().await.at(temp_file)? {
+            records.push(record);
+        }
+        records.sort_unstable_by_key(|record| record.key);
+        for record in &records {
+            rebuilt.write(record).await?;
+        }
+    }
+    let files = rebuilt.finish().await?;
+    info!(files = files.len(), "Unshuffle complete");
+    Ok(files)
+}
+
+fn malformed(path: &Path, record: u64, message: &str) -> ShuffleError {
+    ShuffleError::MalformedInput {
+        path: path.to_path_buf(),
+        record,
+        source: io::Error::new(io::ErrorKind::InvalidData, message),
+    }
+}
+
+/// Removes the files it holds when dropped, whether or not the work succeeded.
+struct RemoveOnDrop(Vec<PathBuf>);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The rebuilt inputs, written one after another as records arrive in key
+/// (and so input) order.
+struct Rebuilt<'a> {
+    report: &'a ShuffleReport,
+    paths: Vec<PathBuf>,
+    /// The input being written: its index, writer, next ordinal and records so far
+    current: Option<(usize, Box<dyn AsyncWrite + Unpin + Send>, u64, u64)>,
+    /// Inputs before this one are finished
+    next_input: usize,
+}
+
+impl<'a> Rebuilt<'a> {
+    fn new(report: &'a ShuffleReport, output_dir: &Path) -> Self {
+        let mut seen: HashMap<&OsStr, usize> = HashMap::new();
+        let paths = report
+            .inputs
+            .iter()
+            .map(|input| {
+                let name = input.path.file_name().unwrap_or(input.path.as_os_str());
+                let occurrence = seen.entry(name).or_default();
+                let mut file_name: OsString = match *occurrence {
+                    0 => Default::default(),
+                    n => format!("{}_", n).into(),
+                };
+                file_name.push(name);
+                *occurrence += 1;
+                output_dir.join(file_name)
+            })
+            .collect();
+        Self { report, paths, current: None, next_input: 0 }
+    }
+
+    async fn write(&mut self, record: &TempRecord) -> Result<(), ShuffleError> {
+        let source = record.source as usize;
+        while self.current.as_ref().is_none_or(|(input, ..)| *input != source) {
+            self.finish_current().await?;
+            self.open_next().await?;
+        }
+        let delimiter = self.report.config.delimiter.as_bytes();
+        let path = &self.paths[source];
+        let (_, writer, next_ordinal, records) = self.current.as_mut().unwrap();
+        if record.ordinal < *next_ordinal {
+            let message = format!("input record {} is in the shards twice", record.ordinal);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message)).at(path);
+        }
+        for _ in *next_ordinal..record.ordinal {
+            writer.write_all(delimiter).await.at(path)?;
+        }
+        writer.write_all(record.line.as_bytes()).await.at(path)?;
+        writer.write_all(delimiter).await.at(path)?;
+        *next_ordinal = record.ordinal + 1;
+        *records += 1;
+        Ok(())
+    }
+
+    /// Finish every input, including those no record came from.
+    async fn finish(mut self) -> Result<Vec<PathBuf>, ShuffleError> {
+        self.finish_current().await?;
+        while self.next_input < self.paths.len() {
+            self.open_next().await?;
+            self.finish_current().await?;
+        }
+        Ok(self.paths)
+    }
+
+    async fn open_next(&mut self) -> Result<(), ShuffleError> {
+        let path = &self.paths[self.next_input];
+        let file = File::create(output::partial_path(path)).await.at(path)?;
+        let writer = compression::output_writer(file, Compression::from_path(path), None);
+        self.current = Some((self.next_input, writer, 0, 0));
+        self.next_input += 1;
+        Ok(())
+    }
+
+    /// Pad the current input with its trailing blank records, check it got
+    /// every record and move it into place.
+    async fn finish_current(&mut self) -> Result<(), ShuffleError> {
+        let Some((input, mut writer, next_ordinal, records)) = self.current.take() else {
+            return Ok(());
+        };
+        let path = &self.paths[input];
+        let stats = &self.report.inputs[input];
+        if records != stats.records {
+            let message = format!("the shards hold {} of its {} records", records, stats.records);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message)).at(path);
+        }
+        for _ in next_ordinal..stats.records + stats.blank_records {
+            writer.write_all(self.report.config.delimiter.as_bytes()).await.at(path)?;
+        }
+        writer.shutdown().await.at(path)?;
+        tokio::fs::rename(output::partial_path(path), path).await.at(path)?;
+        debug!(records, path = %path.display(), "Rebuilt input");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::manifest_path;
+    use crate::shuffle::{shuffle_files, ShardMode, ShuffleConfig};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Shuffle `inputs` into three shards, recording provenance.
+    async fn shuffle(dir: &Path, inputs: Vec<PathBuf>, memory_budget_mb: usize) -> (ShuffleConfig, ShuffleReport) {
+        let out_dir = dir.join("out");
+        let mut config = ShuffleConfig::new(inputs, out_dir.to_str().unwrap(), "shuffled", 64, "\n", "jsonl", Some(5)).unwrap();
+        config.shard_mode = ShardMode::Count(3);
+        config.memory_budget_mb = memory_budget_mb;
+        config.provenance = true;
+        let report = shuffle_files(&config).await.unwrap();
+        (config, report)
+    }
+
+    #[tokio::test]
+    async fn test_sidecars_point_at_input_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.jsonl");
+        let b = temp_dir.path().join("b.jsonl");
+        fs::write(&a, (0..200).map(|i| format!("{{\"a\": {}}}\n", i)).collect::<String>()).unwrap();
+        fs::write(&b, (0..100).map(|i| format!("{{\"b\": {}}}\n", i)).collect::<String>()).unwrap();
+        let (_, report) = shuffle(temp_dir.path(), vec![b.clone(), a.clone()], 64).await;
+
+        let inputs: Vec<Vec<String>> = [&a, &b].iter().map(|path| {
+            fs::read_to_string(path).unwrap().lines().map(String::from).collect()
+        }).collect();
+        for output in &report.outputs {
+            let shard = fs::read_to_string(&output.path).unwrap();
+            let sidecar = fs::read_to_string(output.provenance.as_ref().unwrap()).unwrap();
+            assert_eq!(sidecar.lines().count() as u64, output.records);
+            for (record, origin) in shard.lines().zip(sidecar.lines()) {
+                let (source, ordinal) = origin.split_once('\t').unwrap();
+                assert_eq!(inputs[source.parse::<usize>().unwrap()][ordinal.parse::<usize>().unwrap()], record);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unshuffle_rebuilds_inputs() {
+        let temp_dir = TempDir::new().unwrap();
+        let in_dir = temp_dir.path().join("in");
+        let other_dir = temp_dir.path().join("other");
+        fs::create_dir_all(&in_dir).unwrap();
+        fs::create_dir_all(&other_dir).unwrap();
+        // Several temp buckets' worth, both when shuffling and unshuffling
+        let padding = "x".repeat(200);
+        let plain: String = (0..12000).map(|i| match i % 97 {
+            0 => "\n".to_string(),
+            _ => format!("{{\"id\": {}, \"pad\": \"{}\"}}\n", i, padding),
+        }).collect();
+        let compressed: String = (0..500).map(|i| format!("{{\"other\": {}}}\n", i)).collect();
+        let inputs = vec![in_dir.join("data.jsonl"), in_dir.join("small.jsonl.gz"), other_dir.join("data.jsonl")];
+        fs::write(&inputs[0], &plain).unwrap();
+        let mut writer = compression::output_writer(File::create(&inputs[1]).await.unwrap(), Compression::Gzip, None);
+        writer.write_all(compressed.as_bytes()).await.unwrap();
+        writer.shutdown().await.unwrap();
+        fs::write(&inputs[2], "\n\n").unwrap();
+        let (config, report) = shuffle(temp_dir.path(), inputs, 1).await;
+
+        let rebuilt_dir = temp_dir.path().join("rebuilt");
+        let rebuilt = unshuffle(&manifest_path(&config), &rebuilt_dir, 1).await.unwrap();
+        assert_eq!(rebuilt, vec![rebuilt_dir.join("data.jsonl"), rebuilt_dir.join("small.jsonl.gz"), rebuilt_dir.join("1_data.jsonl")]);
+        assert_eq!(fs::read_to_string(&rebuilt[0]).unwrap(), plain);
+        assert_eq!(fs::read_to_string(&rebuilt[2]).unwrap(), "\n\n");
+        let mut decompressed = String::new();
+        let mut reader = compression::open_input(&rebuilt[1]).await.unwrap();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut decompressed).await.unwrap();
+        assert_eq!(decompressed, compressed);
+        assert_eq!(fs::read_dir(&rebuilt_dir).unwrap().count(), 3);
+
+        // A lost sidecar line is caught
+        let sidecar = report.outputs[1].provenance.clone().unwrap();
+        let origins = fs::read_to_string(&sidecar).unwrap();
+        fs::write(&sidecar, origins.split_once('\n').unwrap().1).unwrap();
+        let error = unshuffle(&manifest_path(&config), &rebuilt_dir, 1).await.unwrap_err();
+        assert!(matches!(error, ShuffleError::MalformedInput { path, .. } if path == sidecar));
+    }
+}
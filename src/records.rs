@@ -0,0 +1,108 @@
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Splits an async byte stream into records separated by an arbitrary
+/// (possibly multi-byte) delimiter.
+///
+/// Records are returned without their trailing delimiter. A final record
+/// that is not followed by a delimiter is still returned. When the delimiter
+/// is `"\n"` a trailing `'\r'` is also stripped, matching `lines()`.
+pub(crate) struct RecordReader<R> {
+    inner: R,
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> RecordReader<R> {
+    pub(crate) fn new(inner: R, delimiter: &str) -> Self {
+        assert!(!delimiter.is_empty(), "record delimiter must not be empty");
+        Self {
+            inner,
+            delimiter: delimiter.as_bytes().to_vec(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Read the next record, or `None` once the stream is exhausted.
+    pub(crate) async fn next_record(&mut self) -> Result<Option<String>, io::Error> {
+        self.buf.clear();
+        let last = *self.delimiter.last().unwrap();
+
+        loop {
+            let read = self.inner.read_until(last, &mut self.buf).await?;
+            if read == 0 {
+                // EOF: hand back whatever is left as the final record
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            if self.buf.ends_with(&self.delimiter) {
+                self.buf.truncate(self.buf.len() - self.delimiter.len());
+                if self.delimiter == b"\n" && self.buf.last() == Some(&b'\r') {
+                    self.buf.pop();
+                }
+                break;
+            }
+        }
+
+        let bytes = std::mem::take(&mut self.buf);
+        String::from_utf8(bytes).map(Some).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn collect(input: &[u8], delimiter: &str, capacity: usize) -> Vec<String> {
+        let mut reader = RecordReader::new(BufReader::with_capacity(capacity, input), delimiter);
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().await.unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[tokio::test]
+    async fn test_newline_delimiter_matches_lines() {
+        let records = collect(b"a\r\nb\nc", "\n", 8192).await;
+        assert_eq!(records, vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_multi_byte_delimiter_keeps_inner_newlines() {
+        let input = b"{\n  \"a\": 1\n}\n\n{\n  \"b\": 2\n}\n\n";
+        let records = collect(input, "\n\n", 8192).await;
+        assert_eq!(records, vec!["{\n  \"a\": 1\n}", "{\n  \"b\": 2\n}"]);
+    }
+
+    #[tokio::test]
+    async fn test_delimiter_split_across_buffer_boundary() {
+        // A capacity of 1 forces every delimiter to straddle refills
+        let records = collect(b"one<|>two<|>three", "<|>", 1).await;
+        assert_eq!(records, vec!["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_partial_delimiter_inside_record() {
+        let records = collect(b"a<|b<|>c", "<|>", 2).await;
+        assert_eq!(records, vec!["a<|b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_record_separator_byte() {
+        let records = collect(b"x\x1ey\x1e", "\x1e", 8192).await;
+        assert_eq!(records, vec!["x", "y"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_is_an_error() {
+        let mut reader = RecordReader::new(BufReader::new(&b"\xff\n"[..]), "\n");
+        let err = reader.next_record().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
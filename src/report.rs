@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::compression::Compression;
+use crate::error::{IoContext, ShuffleError};
+use crate::grouping::GroupKey;
+use crate::mixing::{self, SourceGroup};
+use crate::output;
+use crate::shuffle::{ShardMode, ShuffleConfig, SizeBasis};
+use crate::split::{self, Split};
+
+/// What a finished run did: returned by `shuffle_files` and written as JSON
+/// to the run's manifest (see `manifest_path`) next to the output shards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShuffleReport {
+    /// Version of shuffly that produced the output
+    pub version: String,
+    /// Seed the run used; setting it reproduces the output
+    pub seed: u64,
+    /// Seed of each epoch, when writing more than one; a single-epoch run
+    /// with one of them writes that epoch's records in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub epoch_seeds: Vec<u64>,
+    /// Whether the run picked up where an interrupted one stopped
+    pub resumed: bool,
+    pub config: RunSettings,
+    /// Every source group of a mixed run, in the config's order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<GroupStats>,
+    /// Every split of a split run, in the config's order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub splits: Vec<SplitStats>,
+    /// Every input, sorted by path
+    pub inputs: Vec<InputStats>,
+    /// Every output shard, in order (epoch by epoch, then split by split)
+    pub outputs: Vec<OutputStats>,
+    /// Wall-clock time each phase took; a resumed run only counts its own
+    pub elapsed: PhaseTimes,
+}
+
+/// The settings that decided a run's output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunSettings {
+    pub output_dir: PathBuf,
+    pub output_name: String,
+    pub max_size_mb: usize,
+    pub delimiter: String,
+    pub file_extension: String,
+    pub output_compression: Compression,
+    pub output_compression_level: Option<i32>,
+    pub size_basis: SizeBasis,
+    pub shard_mode: ShardMode,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_groups: Vec<SourceGroup>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mixture_records: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub repeats: BTreeMap<PathBuf, f64>,
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub epochs: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub splits: Vec<Split>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_by: Option<GroupKey>,
+}
+
+/// How one source group of a mixed run came out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupStats {
+    pub name: String,
+    /// Share of the output the group was meant to have
+    pub target_proportion: f64,
+    /// Non-blank records in the group's inputs
+    pub input_records: u64,
+    /// Records of the group in the output, counting repeats and every epoch
+    pub records: u64,
+    /// Share of the output the group has
+    pub proportion: f64,
+}
+
+/// How one split of a split run came out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitStats {
+    pub name: String,
+    /// Share of the records the split was meant to get
+    pub target_fraction: f64,
+    /// Records in the split's shards, counting every epoch
+    pub records: u64,
+    /// Share of the records the split got
+    pub fraction: f64,
+}
+
+/// What was read from one input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputStats {
+    pub path: PathBuf,
+    /// Non-blank records, each shuffled into the output once unless mixing
+    /// or repeating
+    pub records: u64,
+    /// Uncompressed bytes, counting a delimiter after every record
+    pub bytes: u64,
+    /// Blank (or whitespace-only) records, which are dropped
+    pub blank_records: u64,
+    /// Records written to the output of the first epoch, counting every
+    /// copy, when mixing or repeating
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_records: Option<u64>,
+}
+
+/// One output shard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputStats {
+    pub path: PathBuf,
+    /// Epoch the shard belongs to, when writing more than one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<usize>,
+    /// Split the shard belongs to, when splitting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split: Option<String>,
+    pub records: u64,
+    /// Size of the file on disk
+    pub bytes: u64,
+    /// Hex SHA-256 of the file on disk, as printed by `sha256sum`
+    pub sha256: String,
+    /// The shard's provenance sidecar, if the run recorded one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<PathBuf>,
+    /// Records from each source group, when mixing
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub group_records: BTreeMap<String, u64>,
+}
+
+impl OutputStats {
+    /// Share of the shard's records from each source group, when mixing.
+    pub fn group_proportions(&self) -> BTreeMap<String, f64> {
+        self.group_records
+            .iter()
+            .map(|(name, &records)| (name.clone(), records as f64 / self.records.max(1) as f64))
+            .collect()
+    }
+}
+
+/// Seconds spent in each part of a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTimes {
+    /// Checking the journal, estimating the input size and planning buckets
+    pub setup: f64,
+    pub distribute: f64,
+    pub shuffle: f64,
+}
+
+impl ShuffleReport {
+    /// Paths of the output shards, in order.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        self.outputs.iter().map(|output| output.path.clone()).collect()
+    }
+
+    /// Paths of the output shards of `epoch`, in order (all of them for a
+    /// single-epoch run's epoch 0).
+    pub fn epoch_paths(&self, epoch: usize) -> Vec<PathBuf> {
+        self.outputs
+            .iter()
+            .filter(|output| output.epoch.unwrap_or(0) == epoch)
+            .map(|output| output.path.clone())
+            .collect()
+    }
+
+    /// Total records written, over all epochs.
+    pub fn records(&self) -> u64 {
+        self.outputs.iter().map(|output| output.records).sum()
+    }
+
+    /// Read a manifest written by a previous run.
+    pub async fn read(path: &Path) -> Result<Self, ShuffleError> {
+        let json = tokio::fs::read(path).await.at(path)?;
+        serde_json::from_slice(&json).map_err(std::io::Error::from).at(path)
+    }
+
+    /// Write the manifest to `path`, replacing it only once fully written.
+    pub(crate) async fn write(&self, path: &Path) -> Result<(), ShuffleError> {
+        let partial = output::partial_path(path);
+        let mut json = serde_json::to_vec_pretty(self).map_err(std::io::Error::from).at(path)?;
+        json.push(b'\n');
+        tokio::fs::write(&partial, json).await.at(path)?;
+        tokio::fs::rename(&partial, path).await.at(path)
+    }
+}
+
+impl From<&ShuffleConfig> for RunSettings {
+    fn from(config: &ShuffleConfig) -> Self {
+        Self {
+            output_dir: config.output_dir.clone(),
+            output_name: config.output_name.clone(),
+            max_size_mb: config.max_size_mb,
+            delimiter: config.delimiter.clone(),
+            file_extension: config.file_extension.clone(),
+            output_compression: config.output_compression,
+            output_compression_level: config.output_compression_level,
+            size_basis: config.size_basis,
+            shard_mode: config.shard_mode,
+            source_groups: config.source_groups.clone(),
+            mixture_records: config.mixture_records,
+            repeats: config.repeats.clone(),
+            epochs: config.epochs,
+            splits: config.splits.clone(),
+            split_key: config.split_key.clone(),
+            group_by: config.group_by.clone(),
+        }
+    }
+}
+
+fn one() -> usize {
+    1
+}
+
+fn is_one(value: &usize) -> bool {
+    *value == 1
+}
+
+/// Summarise each source group of a mixed run from its inputs and shards.
+pub(crate) fn group_stats(config: &ShuffleConfig, inputs: &[InputStats], outputs: &[OutputStats]) -> Vec<GroupStats> {
+    let total_weight: f64 = config.source_groups.iter().map(|group| group.weight).sum();
+    let total_records: u64 = outputs.iter().map(|output| output.records).sum();
+    let input_groups = mixing::input_groups(config);
+    config
+        .source_groups
+        .iter()
+        .enumerate()
+        .map(|(index, group)| {
+            let records = outputs.iter().filter_map(|output| output.group_records.get(&group.name)).sum();
+            GroupStats {
+                name: group.name.clone(),
+                target_proportion: group.weight / total_weight,
+                input_records: inputs
+                    .iter()
+                    .zip(&input_groups)
+                    .filter(|&(_, &input_group)| input_group == index)
+                    .map(|(input, _)| input.records)
+                    .sum(),
+                records,
+                proportion: records as f64 / total_records.max(1) as f64,
+            }
+        })
+        .collect()
+}
+
+/// Summarise each split of a split run from its shards.
+pub(crate) fn split_stats(config: &ShuffleConfig, outputs: &[OutputStats]) -> Vec<SplitStats> {
+    let total_records: u64 = outputs.iter().map(|output| output.records).sum();
+    config
+        .splits
+        .iter()
+        .zip(split::shares(config))
+        .map(|(split, target_fraction)| {
+            let records = outputs
+                .iter()
+                .filter(|output| output.split.as_deref() == Some(split.name.as_str()))
+                .map(|output| output.records)
+                .sum();
+            SplitStats {
+                name: split.name.clone(),
+                target_fraction,
+                records,
+                fraction: records as f64 / total_records.max(1) as f64,
+            }
+        })
+        .collect()
+}
+
+/// Where the manifest of `config`'s run is written.
+pub fn manifest_path(config: &ShuffleConfig) -> PathBuf {
+    config.output_dir.join(format!("{}.manifest.json", config.output_name))
+}
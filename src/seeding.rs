@@ -0,0 +1,190 @@
+/// SplitMix64 finalizer: a cheap bijective mix with good avalanche.
+pub(crate) fn mix64(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Derive an independent seed for the stream identified by `stream` (for
+/// example a phase and bucket index) from a base seed.
+///
+/// Streams never depend on each other, so work can be split across tasks
+/// in any order without changing what each one draws.
+pub(crate) fn derive_seed(base: u64, stream: &[u64]) -> u64 {
+    stream.iter().fold(mix64(base), |acc, &part| mix64(acc ^ mix64(part)))
+}
+
+/// Stream identifier for the seeds of epochs after the first
+const EPOCH_STREAM: u64 = 3;
+
+/// Seed of epoch `epoch` of a run with seed `seed`: the run's own seed for
+/// epoch 0 and an independent one derived from it for later epochs, so each
+/// epoch's output is what a single-epoch run with that seed would write.
+pub fn epoch_seed(seed: u64, epoch: usize) -> u64 {
+    match epoch {
+        0 => seed,
+        _ => derive_seed(seed, &[EPOCH_STREAM, epoch as u64]),
+    }
+}
+
+/// Stable 64-bit hash of a byte string (FNV-1a, then mixed).
+///
+/// Unlike `std`'s hashers this never changes between builds or platforms,
+/// so it is safe to derive persistent identities from.
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    let fnv = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |acc, &b| {
+        (acc ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    mix64(fnv)
+}
+
+/// A pseudo-random permutation of `0..len` drawn from a seed, evaluated one
+/// index at a time without materialising it.
+///
+/// A four-round Feistel network permutes the smallest square power of two
+/// covering `len`; indices it maps past `len` are mapped again until they
+/// land inside (cycle walking), which takes fewer than four rounds on average.
+#[derive(Debug, Clone)]
+pub(crate) struct Permutation {
+    len: u64,
+    half_bits: u32,
+    round_keys: [u64; 4],
+}
+
+impl Permutation {
+    pub(crate) fn new(seed: u64, len: u64) -> Self {
+        let bits = u64::BITS - len.saturating_sub(1).leading_zeros();
+        Self {
+            len,
+            half_bits: bits.div_ceil(2).max(1),
+            round_keys: [0, 1, 2, 3].map(|round| derive_seed(seed, &[round])),
+        }
+    }
+
+    /// Where `index` (which must be below `len`) goes.
+    pub(crate) fn apply(&self, index: u64) -> u64 {
+        debug_assert!(index < self.len);
+        let mut index = self.feistel(index);
+        while index >= self.len {
+            index = self.feistel(index);
+        }
+        index
+    }
+
+    fn feistel(&self, index: u64) -> u64 {
+        let mask = (1u64 << self.half_bits) - 1;
+        let (mut left, mut right) = (index >> self.half_bits, index & mask);
+        for key in self.round_keys {
+            (left, right) = (right, left ^ (mix64(right ^ key) & mask));
+        }
+        (left << self.half_bits) | right
+    }
+}
+
+/// A half-open range of record sort keys.
+///
+/// Buckets own contiguous key ranges, so concatenating buckets that are each
+/// sorted by key gives the same order however many buckets there were.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyRange {
+    start: u128,
+    end: u128,
+}
+
+impl KeyRange {
+    /// Every possible key
+    pub(crate) const FULL: KeyRange = KeyRange { start: 0, end: 1 << 64 };
+
+    /// Which of `parts` equal slices of this range `key` falls in. Larger
+    /// keys never land in an earlier slice (Lemire's multiply-shift
+    /// reduction when applied to `FULL`).
+    pub(crate) fn part_of(&self, key: u64, parts: usize) -> usize {
+        debug_assert!((self.start..self.end).contains(&(key as u128)));
+        ((key as u128 - self.start) * parts as u128 / (self.end - self.start)) as usize
+    }
+
+    /// The narrowest range holding every key from `low` to `high`.
+    pub(crate) fn spanning(low: u64, high: u64) -> KeyRange {
+        KeyRange { start: low as u128, end: high as u128 + 1 }
+    }
+
+    /// Whether the range holds a single key, so can't be split any further.
+    pub(crate) fn is_single_key(&self) -> bool {
+        self.end - self.start == 1
+    }
+
+    /// The slice of this range holding exactly the keys `part_of` maps to `index`.
+    pub(crate) fn part(&self, index: usize, parts: usize) -> KeyRange {
+        let len = self.end - self.start;
+        KeyRange {
+            start: self.start + (index as u128 * len).div_ceil(parts as u128),
+            end: self.start + ((index as u128 + 1) * len).div_ceil(parts as u128),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_seed_is_stable_and_distinct() {
+        assert_eq!(derive_seed(42, &[1, 2]), derive_seed(42, &[1, 2]));
+        assert_ne!(derive_seed(42, &[1, 2]), derive_seed(42, &[2, 1]));
+        assert_ne!(derive_seed(42, &[1]), derive_seed(43, &[1]));
+        assert_ne!(derive_seed(42, &[0]), derive_seed(42, &[0, 0]));
+    }
+
+    #[test]
+    fn test_part_of_covers_range() {
+        assert_eq!(KeyRange::FULL.part_of(0, 10), 0);
+        assert_eq!(KeyRange::FULL.part_of(u64::MAX, 10), 9);
+        let mut seen = [false; 10];
+        for i in 0..1000 {
+            seen[KeyRange::FULL.part_of(mix64(i), 10)] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    #[test]
+    fn test_parts_are_contiguous_and_consistent() {
+        let bucket = KeyRange::FULL.part(3, 7);
+        let parts: Vec<KeyRange> = (0..5).map(|j| bucket.part(j, 5)).collect();
+        assert_eq!(parts[0].start, bucket.start);
+        assert_eq!(parts[4].end, bucket.end);
+        assert!(parts.windows(2).all(|w| w[0].end == w[1].start));
+
+        for i in 0..10_000 {
+            let key = mix64(i);
+            if KeyRange::FULL.part_of(key, 7) != 3 {
+                continue;
+            }
+            let j = bucket.part_of(key, 5);
+            assert!((parts[j].start..parts[j].end).contains(&(key as u128)));
+        }
+    }
+
+    #[test]
+    fn test_permutation_is_a_bijection() {
+        for len in [1, 2, 3, 1000, 1 << 12] {
+            let permutation = Permutation::new(7, len);
+            let mut seen = vec![false; len as usize];
+            for i in 0..len {
+                let j = permutation.apply(i);
+                assert!(!seen[j as usize], "{} hit twice for len {}", j, len);
+                seen[j as usize] = true;
+            }
+        }
+        let (a, b) = (Permutation::new(1, 1000), Permutation::new(2, 1000));
+        assert!((0..1000).any(|i| a.apply(i) != b.apply(i)));
+        assert!((0..1000).any(|i| a.apply(i) != i));
+    }
+
+    #[test]
+    fn test_hash_bytes_is_pinned() {
+        // Identities derived from this hash must never change
+        assert_eq!(hash_bytes(b""), mix64(0xcbf2_9ce4_8422_2325));
+        assert_ne!(hash_bytes(b"foo.jsonl"), hash_bytes(b"bar.jsonl"));
+    }
+}
@@ -1,13 +1,99 @@
-use std::collections::HashMap;
-use std::path::{PathBuf};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::io;
-use async_compression::tokio::bufread::GzipDecoder;
-use tokio::fs::File;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, Instrument};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
 use rand::prelude::*;
 use rand::rngs::StdRng;
-use rand::{SeedableRng, rng, RngCore};
+use rand::{SeedableRng, rng};
+use serde::{Deserialize, Serialize};
+use crate::bucket::{self, BucketWriters, TempRecord};
+use crate::compression::{self, Compression};
+use crate::error::{IoContext, Phase, ShuffleError};
+use crate::grouping::{self, GroupKey, Grouper};
+use crate::journal::{self, Checkpoint, InputCounts, Journal, JournalState};
+use crate::mixing::{self, Mixture, SourceGroup};
+use crate::output::{self, ShardDigest, ShardPlan, ShardWriter};
+use crate::progress::{ProgressObserver, Reporter};
+use crate::provenance::SidecarReader;
+use crate::records::RecordReader;
+use crate::report::{self, InputStats, OutputStats, PhaseTimes, ShuffleReport};
+use crate::seeding::{self, KeyRange};
+use crate::split::{self, Split, Splitter};
+
+/// Which size `max_size_mb` limits for compressed output shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeBasis {
+    /// Size of the records before compression
+    #[default]
+    Uncompressed,
+    /// Size of the shard on disk, using a sampled compression ratio
+    Compressed,
+}
+
+impl std::str::FromStr for SizeBasis {
+    type Err = ShuffleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uncompressed" => Ok(SizeBasis::Uncompressed),
+            "compressed" => Ok(SizeBasis::Compressed),
+            other => Err(ShuffleError::InvalidConfig(
+                format!("unknown size basis '{}' (expected compressed or uncompressed)", other),
+            )),
+        }
+    }
+}
+
+/// Default for `ShuffleConfig::memory_budget_mb`
+pub const DEFAULT_MEMORY_BUDGET_MB: usize = 4096;
+
+/// Temp files that may be open at once, unless the descriptor limit allows more
+pub(crate) const MAX_OPEN_OUTPUT_FILES: usize = 128;
+
+/// Bounds on each bucket's scatter buffer in phase 1
+pub(crate) const MIN_BUCKET_BUFFER_SIZE: usize = 4 * 1024;
+pub(crate) const MAX_BUCKET_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Bounds on the batches phase 1 readers hand to the scatter stage
+const MIN_BATCH_SIZE: usize = 4 * 1024;
+const MAX_BATCH_SIZE: usize = 4 * 1024 * 1024;
+
+/// How many times an oversized bucket may be split before it is loaded anyway
+const MAX_BUCKET_DEPTH: usize = 8;
+
+/// Least time between phase 1 journal checkpoints
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Stream identifier for record sort keys derived from the run seed
+const KEY_STREAM: u64 = 1;
+
+/// How the shuffled records are divided into output shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardMode {
+    /// One shard per internal bucket; sizes vary randomly around `max_size_mb`
+    /// and buckets that end up empty produce no shard
+    #[default]
+    PerBucket,
+    /// Exactly this many shards whose record counts differ by at most one;
+    /// when splitting, they are shared out by fraction, at least one each
+    Count(usize),
+    /// As many shards as needed, each strictly at most `max_size_mb`
+    /// (measured according to `size_basis`)
+    MaxSize,
+}
 
 #[derive(Debug, Clone)]
 pub struct ShuffleConfig {
@@ -18,6 +104,59 @@ pub struct ShuffleConfig {
     pub delimiter: String,        // Add this field
     pub file_extension: String,   // Add this field
     pub seed: Option<u64>,
+    /// Codec for the final output shards (input codecs are detected per file)
+    pub output_compression: Compression,
+    /// Codec-specific compression level, `None` for the codec default
+    pub output_compression_level: Option<i32>,
+    /// Whether `max_size_mb` counts compressed or uncompressed bytes
+    pub size_basis: SizeBasis,
+    /// Memory for records held in memory: phase 1's scatter buffers and
+    /// batches in flight, and phase 2's loaded buckets (which limits how many
+    /// are shuffled at once; a bucket too big on its own is split)
+    pub memory_budget_mb: usize,
+    /// How records are cut into output shards
+    pub shard_mode: ShardMode,
+    /// How many inputs phase 1 decodes, and how many buckets phase 2 shuffles
+    /// (memory budget permitting), at once
+    pub concurrency: usize,
+    /// Directories for temp buckets, used round-robin; empty means `output_dir`
+    pub temp_dirs: Vec<PathBuf>,
+    /// Leave temp files and the journal behind when a run fails or is
+    /// cancelled, so that it can be resumed
+    pub keep_temp_files: bool,
+    /// Write a provenance sidecar next to every output shard, saying which
+    /// input record each of its records is (see `unshuffle`)
+    pub provenance: bool,
+    /// Mix the inputs in these proportions instead of as one pool; every
+    /// input must be in exactly one group (see `SourceGroup`)
+    pub source_groups: Vec<SourceGroup>,
+    /// Records a mixed run outputs; defaults to the inputs' non-blank records
+    pub mixture_records: Option<u64>,
+    /// How many times the records of these inputs appear in the output
+    /// (others appear once). Fractions sample the last copy, so an input of
+    /// `n` records appears `round(n * multiplier)` times; can't be combined
+    /// with `source_groups`
+    pub repeats: BTreeMap<PathBuf, f64>,
+    /// Independent permutations of the records to write, all from one read
+    /// of the inputs. With more than one, epoch `e` gets the seed
+    /// `epoch_seed(seed, e)` and its shards are named as if `output_name`
+    /// were `<output_name>_epoch<e>`
+    pub epochs: usize,
+    /// Divide the records between these splits instead of writing them all
+    /// to one shard set; each split's shards go to `output_dir/<name>`. A
+    /// record is in the same split in every epoch
+    pub splits: Vec<Split>,
+    /// Dot-separated path of a JSON field (e.g. `meta.url`) whose value
+    /// alone decides a record's split, instead of the seed and the record's
+    /// position; every record must have it
+    pub split_key: Option<String>,
+    /// Shuffle groups of records instead of single records: records with
+    /// the same value of this key stay adjacent, in input order, and in the
+    /// same split. Every record must have one. Can't be combined with
+    /// `source_groups` or `repeats`
+    pub group_by: Option<GroupKey>,
+    /// Told how the run is progressing, see `ProgressObserver`
+    pub progress: Option<Arc<dyn ProgressObserver>>,
 }
 
 impl ShuffleConfig {
@@ -29,12 +168,16 @@ impl ShuffleConfig {
         delimiter: &str,        // Add delimiter parameter
         file_extension: &str,   // Add file extension parameter
         seed: Option<u64>,
-    ) -> Result<Self, io::Error> {
+    ) -> Result<Self, ShuffleError> {
+        if delimiter.is_empty() {
+            return Err(ShuffleError::InvalidConfig("delimiter must not be empty".to_string()));
+        }
+
         let output_dir = PathBuf::from(output_dir);
         
         // Validate output directory exists or can be created
         if !output_dir.exists() {
-            fs::create_dir_all(&output_dir)?;
+            fs::create_dir_all(&output_dir).at(&output_dir)?;
         }
         
         Ok(ShuffleConfig {
@@ -45,273 +188,1940 @@ impl ShuffleConfig {
             delimiter: delimiter.to_string(),           // Store delimiter
             file_extension: file_extension.to_string(), // Store file extension
             seed,
+            output_compression: Compression::None,
+            output_compression_level: None,
+            size_basis: SizeBasis::Uncompressed,
+            memory_budget_mb: DEFAULT_MEMORY_BUDGET_MB,
+            shard_mode: ShardMode::PerBucket,
+            concurrency: default_concurrency(),
+            temp_dirs: Vec::new(),
+            keep_temp_files: false,
+            provenance: false,
+            source_groups: Vec::new(),
+            mixture_records: None,
+            repeats: BTreeMap::new(),
+            epochs: 1,
+            splits: Vec::new(),
+            split_key: None,
+            group_by: None,
+            progress: None,
         })
     }
 }
 
-pub async fn shuffle_files(config: &ShuffleConfig) -> Result<Vec<PathBuf>, io::Error> {
+/// Number of CPUs available to this process, or 1 if unknown.
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Shuffle the records of `config.input_files` into output shards.
+///
+/// # Reproducibility
+///
+/// Every record gets a 64-bit sort key
+/// `derive_seed(derive_seed(seed, [1]), [source, ordinal])`, where `ordinal`
+/// is the record's position in its input file (blank records count) and
+/// `source` identifies the file: a hash of its file name, combined with how
+/// many inputs with the same file name sort before it by full path. The
+/// output is all records in key order, cut into shards by `shard_mode`.
+/// When mixing source groups or repeating inputs, copy `c > 0` of a record
+/// gets the key `derive_seed(derive_seed(seed, [1]), [source, ordinal, c])`.
+/// Each epoch of a multi-epoch run is keyed the same way with its own seed,
+/// `epoch_seed(seed, e)`.
+///
+/// Phase 1 sends each record to the bucket owning its slice of the key range
+/// and phase 2 sorts each bucket by key, so the result for a given seed does
+/// not depend on the order inputs are listed in, the directory they live in,
+/// concurrency, batching, the number of buckets or the memory budget. Only
+/// where `ShardMode::PerBucket` cuts shards depends on the bucket count.
+///
+/// # Failure
+///
+/// Output shards are written under a temporary name and renamed once
+/// complete, so a shard that exists is whole. If the run fails, its temp
+/// files and journal are removed unless `keep_temp_files` is set; a process
+/// that is killed outright leaves them behind and resumes on the next run.
+///
+/// # Report
+///
+/// A finished run writes a `ShuffleReport` of its seed, settings, inputs and
+/// output shards as JSON to `manifest_path(config)`, and returns it.
+pub async fn shuffle_files(config: &ShuffleConfig) -> Result<ShuffleReport, ShuffleError> {
+    shuffle_files_with_cancel(config, &CancellationToken::new()).await
+}
+
+/// Like `shuffle_files`, but gives up with `ShuffleError::Cancelled` once
+/// `cancel` is cancelled, cleaning up as for any other failure.
+pub async fn shuffle_files_with_cancel(
+    config: &ShuffleConfig,
+    cancel: &CancellationToken,
+) -> Result<ShuffleReport, ShuffleError> {
+    let mut cleanup = None;
+    let mut tasks = JoinSet::new();
+    let result = tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(ShuffleError::Cancelled),
+        result = run_shuffle(config, &mut cleanup, &mut tasks) => result,
+    };
+    // A failed or cancelled run leaves its tasks behind; stop them and wait
+    // until they have, so none of them recreates the files the guard removes
+    tasks.abort_all();
+    while tasks.join_next().await.is_some() {}
+    if result.is_ok() {
+        if let Some(cleanup) = cleanup {
+            cleanup.disarm();
+        }
+    }
+    result
+}
+
+async fn run_shuffle(
+    config: &ShuffleConfig,
+    cleanup: &mut Option<CleanupGuard>,
+    tasks: &mut JoinSet<()>,
+) -> Result<ShuffleReport, ShuffleError> {
+    let started = Instant::now();
+    if config.memory_budget_mb == 0 {
+        return Err(ShuffleError::InvalidConfig("memory budget must be at least 1 MB".to_string()));
+    }
+    if config.concurrency == 0 {
+        return Err(ShuffleError::InvalidConfig("concurrency must be at least 1".to_string()));
+    }
+    if config.shard_mode == ShardMode::Count(0) {
+        return Err(ShuffleError::InvalidConfig("shard count must be at least 1".to_string()));
+    }
+    if config.epochs == 0 {
+        return Err(ShuffleError::InvalidConfig("epochs must be at least 1".to_string()));
+    }
+    mixing::validate(config)?;
+    split::validate(config)?;
+    grouping::validate(config)?;
+    
+    let config = Arc::new(config.clone());
+    
+    // A journal left by an interrupted run with the same inputs and settings
+    // is picked up where it stopped; any other is refused rather than mixing
+    // its temp data with this run's
+    let journal_path = journal::journal_path(&config);
+    let fingerprint = journal::fingerprint(&config).await?;
+    let (mut journal, state, estimated_input_size, mixture) = match journal::read(&journal_path).await? {
+        Some(state) if state.fingerprint == fingerprint => {
+            info!(journal = %journal_path.display(), "Resuming interrupted run");
+            *cleanup = CleanupGuard::new(&config);
+            // Phase 1 needs the mixture again if it hasn't finished
+            let mixture = match mixing::mixes(&config) && state.phase_1_records.is_none() {
+                true => Some(Mixture::new(&config, state.seed).await?),
+                false => None,
+            };
+            (Journal::append(&journal_path).await?, state, None, mixture)
+        }
+        Some(_) => return Err(ShuffleError::JournalConflict { path: journal_path }),
+        None => {
+            *cleanup = CleanupGuard::new(&config);
+            // Without an explicit seed, pick one for this run so that every task
+            // derives its randomness from a single value
+            let seed = config.seed.unwrap_or_else(|| rng().random());
+            let mixture = match mixing::mixes(&config) {
+                true => Some(Mixture::new(&config, seed).await?),
+                false => None,
+            };
+            let (estimated_input_size, set_buckets) = plan_buckets(&config, mixture.as_ref()).await?;
+            let state = JournalState::new(fingerprint, seed, set_buckets);
+            (Journal::create(&journal_path, &state).await?, state, Some(estimated_input_size), mixture)
+        }
+    };
+    
+    let resumed = estimated_input_size.is_none();
+    let mut elapsed = PhaseTimes { setup: started.elapsed().as_secs_f64(), ..Default::default() };
+    
     // Phase 1: Distribute lines from input files to temporary files
-    let temp_files = phase_1_distribute(config).await?;
+    let phase_started = Instant::now();
+    let mut reporter = Reporter::distribute(&config, estimated_input_size);
+    let span = reporter.span();
+    let distribution = phase_1_distribute(&config, &state, &mut journal, estimated_input_size, mixture, &mut reporter, tasks)
+        .instrument(span)
+        .await
+        .map_err(|e| e.in_phase(Phase::Distribute))?;
+    elapsed.distribute = phase_started.elapsed().as_secs_f64();
+    
+    let mut sorted_input_files = config.input_files.clone();
+    sorted_input_files.sort();
+    let inputs = distribution
+        .inputs
+        .iter()
+        .map(|(index, counts)| InputStats {
+            path: sorted_input_files[*index].clone(),
+            records: counts.records,
+            bytes: counts.bytes,
+            blank_records: counts.blank,
+            output_records: mixing::mixes(&config).then_some(counts.first_epoch_scattered(config.epochs)),
+        })
+        .collect::<Vec<_>>();
     
     // Phase 2: Shuffle each temp file and write to final output files
-    let output_files = phase_2_shuffle_and_write(config, temp_files).await?;
+    let phase_started = Instant::now();
+    let mut reporter = Reporter::shuffle(&config, distribution.bucket_bytes.iter().sum(), distribution.total_records());
+    let span = reporter.span();
+    let report = async {
+        let mut outputs = Vec::new();
+        for set in 0..state.set_buckets.len() {
+            outputs.extend(phase_2_shuffle_and_write(&config, &distribution, set, &state, &mut journal, &mut reporter, tasks).await?);
+        }
+        reporter.finish();
+        elapsed.shuffle = phase_started.elapsed().as_secs_f64();
+        let report = ShuffleReport {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            seed: state.seed,
+            epoch_seeds: match config.epochs {
+                1 => Vec::new(),
+                epochs => (0..epochs).map(|epoch| seeding::epoch_seed(state.seed, epoch)).collect(),
+            },
+            resumed,
+            config: config.as_ref().into(),
+            groups: report::group_stats(&config, &inputs, &outputs),
+            splits: report::split_stats(&config, &outputs),
+            inputs,
+            outputs,
+            elapsed,
+        };
+        // Written before the journal goes, so a finished run always has one
+        report.write(&report::manifest_path(&config)).await?;
+        journal.remove().await?;
+        Ok(report)
+    }
+    .instrument(span)
+    .await
+    .map_err(|e: ShuffleError| e.in_phase(Phase::Shuffle))?;
     
-    Ok(output_files)
+    Ok(report)
 }
 
-struct LineBuffer {
-    lines: Vec<(usize, String)>, // (temp_file_index, line_content)
-    total_size: usize,
+/// Removes a run's temp files and journal when dropped, unless disarmed
+/// once the run has succeeded.
+struct CleanupGuard {
+    temp_dirs: Vec<PathBuf>,
+    /// Temp buckets, their splits and scratch files all start with this
+    prefix: String,
+    journal_path: PathBuf,
 }
 
-impl LineBuffer {
-    fn new() -> Self {
-        Self {
-            lines: Vec::new(),
-            total_size: 0,
+impl CleanupGuard {
+    /// `None` if the config asks for temp files to be kept.
+    fn new(config: &ShuffleConfig) -> Option<Self> {
+        (!config.keep_temp_files).then(|| Self {
+            temp_dirs: temp_dirs(config),
+            prefix: format!(".{}_temp_", config.output_name),
+            journal_path: journal::journal_path(config),
+        })
+    }
+
+    fn disarm(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        // Best effort: this may run while unwinding, so errors are ignored
+        for temp_dir in &self.temp_dirs {
+            let Ok(entries) = fs::read_dir(temp_dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&self.prefix) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
         }
+        let _ = fs::remove_file(&self.journal_path);
     }
-    
-    fn add_line(&mut self, temp_index: usize, line: String) {
-        self.total_size += line.len();
-        self.lines.push((temp_index, line));
+}
+
+/// A task spawned into a run's `JoinSet` that is aborted if dropped before
+/// it is joined, so that a failed or cancelled run doesn't leave work running
+/// in the background. The set lets the run wait for aborted tasks to stop.
+struct TaskGuard<T> {
+    result: oneshot::Receiver<T>,
+    abort: AbortHandle,
+}
+
+impl<T: Send + 'static> TaskGuard<T> {
+    fn spawn(tasks: &mut JoinSet<()>, task: impl std::future::Future<Output = T> + Send + 'static) -> Self {
+        let (sender, result) = oneshot::channel();
+        let abort = tasks.spawn(async move {
+            let _ = sender.send(task.await);
+        });
+        Self { result, abort }
     }
-    
-    fn is_full(&self, max_size: usize) -> bool {
-        self.total_size >= max_size
+
+    async fn join(mut self) -> Result<T, io::Error> {
+        // The sender only goes without sending if the task panicked
+        (&mut self.result).await.map_err(|_| io::Error::other("task panicked"))
+    }
+}
+
+impl<T> Drop for TaskGuard<T> {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Temp buckets produced by phase 1.
+///
+/// Buckets are grouped into output sets, one for each split of each epoch
+/// (epoch by epoch), each of which becomes its own set of shards.
+struct Distribution {
+    /// Temp buckets of every output set, in set order
+    temp_files: Vec<PathBuf>,
+    /// Size of each temp bucket
+    bucket_bytes: Vec<u64>,
+    /// Records in each temp bucket
+    bucket_records: Vec<u64>,
+    /// Buckets of each output set
+    set_buckets: Vec<usize>,
+    /// Records in the buckets of each output set
+    set_records: Vec<usize>,
+    /// What was read from each input (by sorted index), in index order
+    inputs: Vec<(usize, InputCounts)>,
+}
+
+impl Distribution {
+    fn new(
+        temp_files: Vec<PathBuf>,
+        (bucket_bytes, bucket_records): (Vec<u64>, Vec<u64>),
+        state: &JournalState,
+        mut inputs: Vec<(usize, InputCounts)>,
+    ) -> Self {
+        inputs.sort_by_key(|&(index, _)| index);
+        let set_records = (0..state.set_buckets.len())
+            .map(|set| inputs.iter().map(|(_, counts)| counts.scattered[set] as usize).sum())
+            .collect();
+        Self { temp_files, bucket_bytes, bucket_records, set_buckets: state.set_buckets.clone(), set_records, inputs }
+    }
+
+    fn total_records(&self) -> usize {
+        self.set_records.iter().sum()
+    }
+
+    /// Bucket numbers of output set `set`.
+    fn set_range(&self, set: usize) -> Range<usize> {
+        let first = self.set_buckets[..set].iter().sum();
+        first..first + self.set_buckets[set]
     }
+}
+
+/// Where phase 1 sends records: the output set of each split of each epoch,
+/// and a bucket within it chosen by the record's key.
+struct Scatter {
+    /// Seed of each epoch's sort keys
+    key_seeds: Vec<u64>,
+    splitter: Splitter,
+    grouper: Option<Grouper>,
+    /// Buckets of each output set
+    set_buckets: Vec<usize>,
+    /// Number of each output set's first bucket
+    first_buckets: Vec<usize>,
+}
+
+impl Scatter {
+    fn new(config: &ShuffleConfig, state: &JournalState) -> Result<Self, ShuffleError> {
+        let first_buckets = state
+            .set_buckets
+            .iter()
+            .scan(0, |first, &buckets| Some(std::mem::replace(first, *first + buckets)))
+            .collect();
+        Ok(Self {
+            key_seeds: (0..config.epochs)
+                .map(|epoch| seeding::derive_seed(seeding::epoch_seed(state.seed, epoch), &[KEY_STREAM]))
+                .collect(),
+            splitter: Splitter::new(config, state.seed),
+            grouper: config.group_by.as_ref().map(Grouper::new).transpose()?,
+            set_buckets: state.set_buckets.clone(),
+            first_buckets,
+        })
+    }
+
+    fn num_sets(&self) -> usize {
+        self.set_buckets.len()
+    }
+
+    /// Output set of `split` in `epoch`.
+    fn set(&self, epoch: usize, split: usize) -> usize {
+        epoch * (self.num_sets() / self.key_seeds.len()) + split
+    }
+
+    /// Bucket of a record with `key` in output set `set`.
+    fn bucket(&self, set: usize, key: u64) -> usize {
+        self.first_buckets[set] + KeyRange::FULL.part_of(key, self.set_buckets[set])
+    }
+}
+
+/// Decide how many temp buckets to use, returning the estimated input size
+/// and the bucket count of each output set. Fails if the temp directories
+/// look too small.
+///
+/// A mixed run's "input" is what the mixture scatters, which is known exactly.
+/// Each split gets buckets for its share of the input, and every epoch as
+/// many as a single-epoch run would, so that each epoch's shards are cut the
+/// same way.
+async fn plan_buckets(config: &ShuffleConfig, mixture: Option<&Mixture>) -> Result<(usize, Vec<usize>), ShuffleError> {
+    // Estimate number of output files based on total input size
+    let estimated_input_size = match mixture {
+        Some(mixture) => mixture.output_bytes() as usize,
+        None => estimate_total_input_size(&config.input_files).await?,
+    };
+    info!(bytes = estimated_input_size, "Estimated uncompressed input size");
+    let sample = RecordSample::read(config).await?;
+    let split_files: Vec<usize> = if config.shard_mode == ShardMode::PerBucket {
+        // Buckets become the output shards, so size them by max_size_mb
+        let mut total_input_size = estimated_input_size;
+        if config.size_basis == SizeBasis::Compressed && config.output_compression != Compression::None {
+            let ratio = sample.compression_ratio(config).await?;
+            info!(codec = %config.output_compression, ratio = format_args!("{:.3}", ratio), "Sampled compression ratio");
+            total_input_size = (total_input_size as f64 * ratio).ceil() as usize;
+        }
+        let max_size_bytes = config.max_size_mb * 1024 * 1024;
+        let split_files = split_sizes(config, total_input_size).map(|size| size.div_ceil(max_size_bytes).max(1)).collect();
+        info!(files = ?split_files, "Estimated output files needed");
+        split_files
+    } else {
+        // Shards are cut independently, so buckets only need to fit in
+        // memory, with room for per-record overhead once loaded
+        let memory_budget = config.memory_budget_mb * 1024 * 1024;
+        let split_files = split_sizes(config, estimated_input_size)
+            .map(|size| size.div_ceil((memory_budget / 2).max(1)).max(1))
+            .collect();
+        info!(files = ?split_files, "Using temp files");
+        split_files
+    };
     
-    fn clear(&mut self) {
-        self.lines.clear();
-        self.total_size = 0;
+    let temp_dirs = temp_dirs(config);
+    for temp_dir in &temp_dirs {
+        tokio::fs::create_dir_all(temp_dir).await.at(temp_dir)?;
     }
+    let set_buckets: Vec<usize> = (0..config.epochs).flat_map(|_| split_files.iter().copied()).collect();
+    // Fail before anything is written if phase 1 can't buffer this many buckets
+    phase_1_buffer_sizes(config, set_buckets.iter().sum())?;
+    // Temp records lose their delimiter but gain a header
+    let estimated_records = sample.records_in(estimated_input_size as u64);
+    let estimated_temp_size = (estimated_input_size as u64 + estimated_records * bucket::HEADER_LEN as u64) * config.epochs as u64;
+    check_free_space(&temp_dirs, set_buckets.iter().sum(), estimated_temp_size)?;
     
-    fn is_empty(&self) -> bool {
-        self.lines.is_empty()
+    Ok((estimated_input_size, set_buckets))
+}
+
+/// The share of `size` each split of `config` expects, or all of it without
+/// splits.
+fn split_sizes(config: &ShuffleConfig, size: usize) -> impl Iterator<Item = usize> + '_ {
+    split::shares(config).into_iter().map(move |share| (size as f64 * share).ceil() as usize)
+}
+
+/// Directories temp buckets are striped across.
+fn temp_dirs(config: &ShuffleConfig) -> Vec<PathBuf> {
+    match config.temp_dirs.as_slice() {
+        [] => vec![config.output_dir.clone()],
+        dirs => dirs.to_vec(),
     }
 }
 
-async fn flush_line_buffer(
-    buffer: &mut LineBuffer,
-    temp_files: &[PathBuf],
-    max_open_files: usize,
-		delimiter: &str
-) -> Result<(), io::Error> {
-    if buffer.is_empty() {
-        return Ok(());
+/// Paths of the temp buckets, assigned round-robin to the temp directories.
+fn temp_file_paths(config: &ShuffleConfig, num_buckets: usize) -> Vec<PathBuf> {
+    let temp_dirs = temp_dirs(config);
+    (0..num_buckets)
+        .map(|i| {
+            temp_dirs[i % temp_dirs.len()].join(format!(".{}_temp_{:04}.{}", 
+                config.output_name, i, config.file_extension))
+        })
+        .collect()
+}
+
+/// Scatter every input record into the temp buckets.
+///
+/// Progress is checkpointed to the journal at most every
+/// `CHECKPOINT_INTERVAL` as inputs finish: bucket files are flushed and
+/// their lengths recorded along with the finished inputs. A resumed run
+/// truncates the buckets back to the last checkpoint and rereads only the
+/// inputs it doesn't list.
+async fn phase_1_distribute(
+    config: &Arc<ShuffleConfig>,
+    state: &JournalState,
+    journal: &mut Journal,
+    estimated_input_size: Option<usize>,
+    mixture: Option<Mixture>,
+    reporter: &mut Reporter,
+    tasks: &mut JoinSet<()>,
+) -> Result<Distribution, ShuffleError> {
+    info!("Phase 1: Distributing lines to temporary files");
+    
+    let temp_files = temp_file_paths(config, state.num_buckets());
+    if let (Some(total_records), Some(checkpoint)) = (state.phase_1_records, &state.checkpoint) {
+        info!(records = total_records, temp_files = temp_files.len(), "Phase 1 already complete");
+        let buckets = (checkpoint.lengths.clone(), checkpoint.records.clone());
+        return Ok(Distribution::new(temp_files, buckets, state, checkpoint.inputs.clone()));
     }
     
-    // Group lines by temp file index
-    let mut lines_by_file: HashMap<usize, Vec<String>> = HashMap::new();
-    for (temp_index, line) in buffer.lines.drain(..) {
-        lines_by_file.entry(temp_index).or_default().push(line);
+    // Start from the last checkpoint, dropping anything written after it
+    let mut completed_inputs = Vec::new();
+    match &state.checkpoint {
+        Some(checkpoint) => {
+            for (temp_file, &length) in temp_files.iter().zip(&checkpoint.lengths) {
+                let file = OpenOptions::new().write(true).open(temp_file).await.at(temp_file)?;
+                if file.metadata().await.at(temp_file)?.len() < length {
+                    let message = format!(
+                        "shorter than the journal says; delete {} to start over",
+                        journal::journal_path(config).display()
+                    );
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message)).at(temp_file);
+                }
+                file.set_len(length).await.at(temp_file)?;
+            }
+            completed_inputs = checkpoint.inputs.clone();
+            info!(inputs = completed_inputs.len(), "Skipping inputs finished before the interruption");
+        }
+        None => {
+            // Create empty temp files (but don't keep them open)
+            for temp_file in &temp_files {
+                File::create(temp_file).await.at(temp_file)?;
+            }
+        }
     }
+
+    // Process input files in sorted order for deterministic behavior
+    let mut sorted_input_files = config.input_files.clone();
+    sorted_input_files.sort();
+    let identities = Arc::new(source_identities(&sorted_input_files));
+    let mixture = mixture.map(Arc::new);
+    let pending_inputs: Vec<usize> = (0..sorted_input_files.len())
+        .filter(|i| !completed_inputs.iter().any(|(done, _)| done == i))
+        .collect();
+    let pending_inputs = Arc::new(pending_inputs);
+    let sorted_input_files = Arc::new(sorted_input_files);
     
-    // Process files in batches to limit open file descriptors
-    let file_indices: Vec<usize> = lines_by_file.keys().cloned().collect();
+    // Readers decode inputs in parallel and hand batches to this task, which
+    // is the only one touching the temp files
+    let scatter = Arc::new(Scatter::new(config, state)?);
+    let (bucket_buffer_size, batch_size) = phase_1_buffer_sizes(config, temp_files.len())?;
+    let next_input = Arc::new(AtomicUsize::new(0));
+    let (sender, mut receiver) = mpsc::channel(config.concurrency * 2);
+    let mut readers = Vec::new();
+    for _ in 0..config.concurrency.min(pending_inputs.len()) {
+        readers.push(TaskGuard::spawn(tasks, read_inputs(
+            config.clone(),
+            sorted_input_files.clone(),
+            identities.clone(),
+            mixture.clone(),
+            pending_inputs.clone(),
+            next_input.clone(),
+            scatter.clone(),
+            batch_size,
+            sender.clone(),
+        ).in_current_span()));
+    }
+    drop(sender);
     
-    for chunk in file_indices.chunks(max_open_files) {
-        let mut writers = Vec::new();
-        let mut indices = Vec::new();
-        
-        // Open files in this batch
-        for &file_idx in chunk {
-            let file = tokio::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&temp_files[file_idx])
-                .await?;
-            writers.push(BufWriter::new(file));
-            indices.push(file_idx);
+    let max_open = bucket::max_open_buckets(MAX_OPEN_OUTPUT_FILES);
+    let mut writers = BucketWriters::new(temp_files.clone(), bucket_buffer_size, max_open);
+    if let Some(checkpoint) = &state.checkpoint {
+        writers.restore(checkpoint.lengths.clone(), checkpoint.records.clone());
+    }
+    let mut last_checkpoint = Instant::now();
+    while let Some(batch) = receiver.recv().await {
+        reporter.advance(batch.bytes as u64, batch.records.len() as u64, &sorted_input_files[batch.source]);
+        for (temp_index, record) in batch.records {
+            writers.push(temp_index, &record).await?;
         }
-        
-        // Write all lines for files in this batch
-    for (writer_idx, &file_idx) in indices.iter().enumerate() {
-        if let Some(lines) = lines_by_file.get(&file_idx) {
-            for line in lines {
-                writers[writer_idx].write_all(line.as_bytes()).await?;
-                writers[writer_idx].write_all(delimiter.as_bytes()).await?;  // Use configured delimiter
+        if let Some(finished_input) = batch.finished_input {
+            completed_inputs.push(finished_input);
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                checkpoint_buckets(&mut writers, journal, &completed_inputs).await?;
+                last_checkpoint = Instant::now();
             }
         }
     }
-        
-        // Flush and close all writers in this batch
-        for mut writer in writers {
-            writer.flush().await?;
-        }
+    let (bucket_bytes, bucket_records) = checkpoint_buckets(&mut writers, journal, &completed_inputs).await?;
+    drop(writers);
+    
+    let mut total_bytes_read = 0;
+    for reader in readers {
+        total_bytes_read += reader.join().await??;
     }
+    let total_lines = completed_inputs.iter().map(|(_, counts)| counts.total_scattered() as usize).sum();
+    journal.record_phase_1(total_lines).await?;
+    reporter.finish();
     
-    buffer.clear();
-    Ok(())
+    info!(records = total_lines, temp_files = temp_files.len(), "Phase 1 complete");
+    if let Some(estimated_input_size) = estimated_input_size {
+        info!(
+            estimated = estimated_input_size,
+            actual = total_bytes_read,
+            ratio = format_args!("{:.2}", total_bytes_read as f64 / estimated_input_size.max(1) as f64),
+            "Input size"
+        );
+    }
+    
+    Ok(Distribution::new(temp_files, (bucket_bytes, bucket_records), state, completed_inputs))
+}
+
+/// Flush every bucket and journal their state along with the inputs that
+/// are now fully scattered. Returns the size and record count of each bucket.
+async fn checkpoint_buckets(
+    writers: &mut BucketWriters,
+    journal: &mut Journal,
+    completed_inputs: &[(usize, InputCounts)],
+) -> Result<(Vec<u64>, Vec<u64>), ShuffleError> {
+    let (lengths, records) = writers.checkpoint().await?;
+    let checkpoint = Checkpoint {
+        lengths,
+        records,
+        inputs: completed_inputs.to_vec(),
+    };
+    journal.record_checkpoint(&checkpoint).await?;
+    Ok((checkpoint.lengths, checkpoint.records))
 }
 
-async fn phase_1_distribute(config: &ShuffleConfig) -> Result<Vec<PathBuf>, io::Error> {
-    println!("Phase 1: Distributing lines to temporary files...");
+/// Fail early if the temp directories can't hold the buckets.
+///
+/// Buckets hold every record with its framing, `estimated_temp_size` bytes
+/// in all, which gets a 25% margin for estimation error. Directories on the
+/// same filesystem are checked together; filesystems whose free space can't
+/// be determined are skipped.
+fn check_free_space(temp_dirs: &[PathBuf], num_buckets: usize, estimated_temp_size: u64) -> Result<(), ShuffleError> {
+    let needed_per_bucket = (estimated_temp_size + estimated_temp_size / 4).div_ceil(num_buckets as u64);
     
-    // Estimate number of output files based on total input size
-    let total_input_size = estimate_total_input_size(&config.input_files).await?;
-    let max_size_bytes = config.max_size_mb * 1024 * 1024;
-    let estimated_num_files = ((total_input_size + max_size_bytes - 1) / max_size_bytes).max(1);
+    // Filesystem -> (a directory on it, bytes needed there)
+    let mut needed: HashMap<u64, (&Path, u64)> = HashMap::new();
+    for (i, temp_dir) in temp_dirs.iter().enumerate() {
+        let Some(filesystem) = bucket::filesystem_id(temp_dir) else {
+            continue;
+        };
+        let buckets = num_buckets / temp_dirs.len() + usize::from(i < num_buckets % temp_dirs.len());
+        needed.entry(filesystem).or_insert((temp_dir, 0)).1 += needed_per_bucket * buckets as u64;
+    }
     
-    println!("Estimated {} output files needed", estimated_num_files);
+    for (temp_dir, needed) in needed.into_values() {
+        if let Some(available) = bucket::available_space(temp_dir) {
+            if available < needed {
+                let message = format!("temp directory has {} bytes free but about {} are needed", available, needed);
+                return Err(io::Error::new(io::ErrorKind::StorageFull, message)).at(temp_dir);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How phase 1 spends `memory_budget_mb`: half on per-bucket scatter
+/// buffers, half on record batches between readers and the scatter stage
+/// (one being filled per reader, two queued per reader and one being
+/// scattered). Returns the size of each bucket buffer and of each batch.
+fn phase_1_buffer_sizes(config: &ShuffleConfig, num_buckets: usize) -> Result<(usize, usize), ShuffleError> {
+    let half_budget = config.memory_budget_mb * 1024 * 1024 / 2;
+    let bucket_buffer_size = bucket_buffer_size(half_budget, num_buckets)?;
+    let batch_size = (half_budget / (3 * config.concurrency + 1)).clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE);
+    Ok((bucket_buffer_size, batch_size))
+}
+
+/// Size of each of `num_buckets` scatter buffers sharing `budget` bytes.
+/// Fails if even the smallest useful buffers would take more than that.
+pub(crate) fn bucket_buffer_size(budget: usize, num_buckets: usize) -> Result<usize, ShuffleError> {
+    if MIN_BUCKET_BUFFER_SIZE * num_buckets > budget {
+        return Err(ShuffleError::InvalidConfig(format!(
+            "memory_budget_mb is too small for {} temp buckets, whose buffers need {} MB; raise it (or max_size_mb)",
+            num_buckets,
+            (2 * MIN_BUCKET_BUFFER_SIZE * num_buckets).div_ceil(1024 * 1024),
+        )));
+    }
+    Ok((budget / num_buckets).min(MAX_BUCKET_BUFFER_SIZE))
+}
+
+/// Stable identity of each input for deriving sort keys: a hash of the file
+/// name plus how many earlier inputs (in the given, sorted order) share it.
+fn source_identities(sorted_inputs: &[PathBuf]) -> Vec<u64> {
+    let mut seen: HashMap<&OsStr, u64> = HashMap::new();
+    sorted_inputs
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            let occurrence = seen.entry(name).or_default();
+            let identity = seeding::derive_seed(seeding::hash_bytes(name.as_encoded_bytes()), &[*occurrence]);
+            *occurrence += 1;
+            identity
+        })
+        .collect()
+}
+
+/// Records read by a phase 1 worker, each with its bucket.
+struct Batch {
+    /// Index of the input the records come from
+    source: usize,
+    records: Vec<(usize, TempRecord)>,
+    /// Input bytes read since the previous batch
+    bytes: usize,
+    /// On the last batch of an input: its index and what was read from it
+    finished_input: Option<(usize, InputCounts)>,
+}
+
+/// Phase 1 worker: claim input files one at a time from `pending` (indices
+/// into `inputs`), decode them and send each non-blank record to the
+/// scatter stage with its bucket, as many times as `mixture` says (once
+/// without one), for every epoch.
+///
+/// A record's split, key and so bucket depend only on the seeds, the
+/// identity of its input file and its position in that file (or its group
+/// and split key), never on which worker read it or when. Each further copy
+/// of a record gets a key of its own, so copies land independently across
+/// the output. Batches are cut at `batch_size` bytes of memory,
+/// counting the records' allocations and not just their text, and at the end
+/// of every input. Returns the number of bytes read.
+#[allow(clippy::too_many_arguments)]
+async fn read_inputs(
+    config: Arc<ShuffleConfig>,
+    inputs: Arc<Vec<PathBuf>>,
+    identities: Arc<Vec<u64>>,
+    mixture: Option<Arc<Mixture>>,
+    pending: Arc<Vec<usize>>,
+    next_input: Arc<AtomicUsize>,
+    scatter: Arc<Scatter>,
+    batch_size: usize,
+    sender: mpsc::Sender<Batch>,
+) -> Result<usize, ShuffleError> {
+    let mut total_bytes_read = 0;
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    let mut batch_line_bytes = 0;
     
-    // Create temp file paths (but don't open them yet)
-    let mut temp_files = Vec::new();
-    for i in 0..estimated_num_files {
-        let temp_path = config.output_dir.join(format!(".{}_temp_{:04}.{}", 
-            config.output_name, i, config.file_extension));
-        temp_files.push(temp_path);
+    while let Some(&source) = pending.get(next_input.fetch_add(1, Ordering::Relaxed)) {
+        let input_file = &inputs[source];
+        debug!(input = %input_file.display(), "Processing input");
+        
+        let reader = compression::open_input(input_file).await.map_err(|e| ShuffleError::opening_input(input_file, e))?;
+        let mut records = RecordReader::new(reader, &config.delimiter);
+        let mut ordinal = 0u64;
+        let mut counts = InputCounts::new(scatter.num_sets());
+        
+        while let Some(mut line) = records.next_record().await.map_err(|e| ShuffleError::reading_input(input_file, ordinal, e))? {
+            total_bytes_read += line.len() + config.delimiter.len();
+            batch_bytes += line.len() + config.delimiter.len();
+            counts.bytes += (line.len() + config.delimiter.len()) as u64;
+            if line.trim().is_empty() {
+                counts.blank += 1;
+            } else {
+                // A group's records all take its hash in place of their
+                // position, so they share their split and keys
+                let group = scatter.grouper.as_ref().map(|grouper| grouper.group_hash(&line)).transpose();
+                let group = group.map_err(|e| ShuffleError::reading_input(input_file, ordinal, e))?;
+                let position = [identities[source], ordinal];
+                let origin = group.as_ref().map_or(&position[..], std::slice::from_ref);
+                let split = scatter.splitter.assign(&line, origin).map_err(|e| ShuffleError::reading_input(input_file, ordinal, e))?;
+                for (epoch, &key_seed) in scatter.key_seeds.iter().enumerate() {
+                    let set = scatter.set(epoch, split);
+                    let copies = mixture.as_ref().map_or(1, |mixture| mixture.copies(epoch, source, counts.records));
+                    for copy in 0..copies {
+                        let key = match copy {
+                            0 => seeding::derive_seed(key_seed, origin),
+                            _ => seeding::derive_seed(key_seed, &[identities[source], ordinal, copy as u64]),
+                        };
+                        let temp_index = scatter.bucket(set, key);
+                        let last = epoch + 1 == scatter.key_seeds.len() && copy + 1 == copies;
+                        let line = if last { std::mem::take(&mut line) } else { line.clone() };
+                        let record = TempRecord { key, source: source as u32, ordinal, copy, line };
+                        batch_line_bytes += record.line.capacity();
+                        batch.push((temp_index, record));
+                        
+                        if batch.capacity() * size_of::<(usize, TempRecord)>() + batch_line_bytes >= batch_size {
+                            send_batch(&sender, source, std::mem::take(&mut batch), std::mem::take(&mut batch_bytes), None).await?;
+                            batch_line_bytes = 0;
+                        }
+                    }
+                    counts.scattered[set] += copies as u64;
+                }
+                counts.records += 1;
+            }
+            ordinal += 1;
+        }
+        
+        let finished_input = Some((source, counts));
+        send_batch(&sender, source, std::mem::take(&mut batch), std::mem::take(&mut batch_bytes), finished_input).await?;
+        batch_line_bytes = 0;
     }
+    
+    Ok(total_bytes_read)
+}
 
-    // Configuration for batched processing
-    const MAX_OPEN_INPUT_FILES: usize = 16;
-    const MAX_OPEN_OUTPUT_FILES: usize = 128;
-    const MAX_BUFFER_SIZE: usize = 1024 * 1024 * 1024; // 1GB
+async fn send_batch(
+    sender: &mpsc::Sender<Batch>,
+    source: usize,
+    records: Vec<(usize, TempRecord)>,
+    bytes: usize,
+    finished_input: Option<(usize, InputCounts)>,
+) -> Result<(), io::Error> {
+    // The receiver only goes away when the scatter stage has failed
+    sender
+        .send(Batch { source, records, bytes, finished_input })
+        .await
+        .map_err(|_| io::Error::other("phase 1 scatter stage stopped"))
+}
+
+/// Shuffle every bucket of output set `set` (see `Distribution`) and write
+/// its output shards.
+///
+/// Progress is journaled as buckets are consumed, so a resumed run skips
+/// finished work. With `ShardMode::PerBucket` each bucket's shard is
+/// recorded and its temp file removed. Otherwise buckets feed one stream of
+/// records cut into shards: each closed shard and each consumed bucket's
+/// record count is recorded, and a temp file is only removed once all of
+/// its records are in closed shards. A resumed run restarts the stream at
+/// the first remaining bucket and skips the records already in closed shards.
+///
+/// Buckets are numbered within the set, except in the journal, which
+/// numbers them across all sets as phase 1 does.
+async fn phase_2_shuffle_and_write(
+    config: &Arc<ShuffleConfig>,
+    distribution: &Distribution,
+    set: usize,
+    state: &JournalState,
+    journal: &mut Journal,
+    reporter: &mut Reporter,
+    tasks: &mut JoinSet<()>,
+) -> Result<Vec<OutputStats>, ShuffleError> {
+    let num_splits = distribution.set_buckets.len() / config.epochs;
+    let (epoch, split) = (set / num_splits, config.splits.get(set % num_splits));
+    info!(epoch, split = split.map(|split| split.name.as_str()), "Phase 2: Shuffling temp files and writing final output");
     
-    // Initialize RNG with seed for deterministic behavior
-    let mut rng: Box<dyn RngCore> = match config.seed {
-        Some(seed) => Box::new(StdRng::seed_from_u64(seed)),
-        None => Box::new(rng()),
+    let buckets = distribution.set_range(set);
+    let first_bucket = buckets.start;
+    let temp_files = &distribution.temp_files[buckets.clone()];
+    let bucket_bytes = &distribution.bucket_bytes[buckets.clone()];
+    let bucket_records = &distribution.bucket_records[buckets];
+    let total_records = distribution.set_records[set];
+    let closed_shards = state.closed_shards.get(&set).map_or(&[][..], Vec::as_slice);
+    let config = &set_config(config, epoch, split);
+    if split.is_some() {
+        tokio::fs::create_dir_all(&config.output_dir).await.at(&config.output_dir)?;
+    }
+    let mut output_files = Vec::new();
+    
+    // Buckets hold consecutive key ranges, so concatenating them in order is
+    // the full key order, which can be cut anywhere
+    let plan = match config.shard_mode {
+        ShardMode::PerBucket => None,
+        ShardMode::Count(num_shards) => {
+            let num_shards = match split {
+                Some(_) => split::shard_counts(config, num_shards)[set % num_splits],
+                None => num_shards,
+            };
+            let base = total_records / num_shards;
+            let extra = total_records % num_shards;
+            let counts = (0..num_shards).map(|i| base + usize::from(i < extra)).collect();
+            Some(ShardPlan::Counts(counts))
+        }
+        ShardMode::MaxSize => Some(ShardPlan::MaxBytes(config.max_size_mb as u64 * 1024 * 1024)),
     };
-    let mut total_lines = 0;
-    let mut line_buffer = LineBuffer::new();
+    let mut shards = plan.map(|plan| {
+        let closed = closed_shards.iter().enumerate().map(|(k, shard)| (plan.shard_path(config, k), shard.clone()));
+        let closed = closed.collect();
+        ShardWriter::resume(config, plan, closed)
+    });
     
-    // Process input files in sorted order for deterministic behavior
-    let mut sorted_input_files = config.input_files.clone();
-    sorted_input_files.sort();
+    // Where the record stream picks up: buckets are removed in order, so
+    // every bucket before the first remaining one was consumed
+    let mut next_bucket = 0;
+    let mut stream_position = 0u64;
+    let mut skip_records = 0u64;
+    let mut journaled_shards = closed_shards.len();
+    let mut consumed_buckets = VecDeque::new();
+    if shards.is_some() {
+        while next_bucket < temp_files.len() && !tokio::fs::try_exists(&temp_files[next_bucket]).await.at(&temp_files[next_bucket])? {
+            stream_position += state.consumed_buckets.get(&(first_bucket + next_bucket)).copied()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "temp file is missing"))
+                .at(&temp_files[next_bucket])?;
+            next_bucket += 1;
+        }
+        let closed_records: u64 = closed_shards.iter().map(|shard| shard.records as u64).sum();
+        skip_records = closed_records.checked_sub(stream_position).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "journal records fewer closed shards than removed temp files")
+        })?;
+    }
+    let mut per_bucket_outputs = Vec::new();
     
-    // Process input files in batches
-    for input_batch in sorted_input_files.chunks(MAX_OPEN_INPUT_FILES) {
-        let mut readers = Vec::new();
-        
-        // Open input files in this batch
-        for input_file in input_batch {
-            println!("Processing {}", input_file.display());
-            
-            let file = File::open(input_file).await?;
-            let buf_reader = BufReader::new(file);
+    // Memory is reserved in KiB before a bucket is loaded and only released
+    // once its records have been written, so finished-but-unwritten buckets
+    // count against the budget too
+    let memory_budget_kib = (config.memory_budget_mb * 1024).min(u32::MAX as usize) as u32;
+    let memory = Arc::new(Semaphore::new(memory_budget_kib as usize));
+    let mut in_flight = VecDeque::new();
+    
+    loop {
+        // Start buckets in order while there is concurrency and memory to spare
+        while next_bucket < temp_files.len() && in_flight.len() < config.concurrency {
+            let temp_file = temp_files[next_bucket].clone();
+            if let Some(shard) = state.written_buckets.get(&(first_bucket + next_bucket)) {
+                // Written before the interruption; the temp file may outlive the journal entry
+                if shard.records > 0 {
+                    let path = output::output_path(config, next_bucket, temp_files.len() > 1);
+                    per_bucket_outputs.push((next_bucket, path, shard.clone()));
+                }
+                reporter.advance(bucket_bytes[next_bucket], shard.records as u64, &temp_file);
+                if tokio::fs::try_exists(&temp_file).await.at(&temp_file)? {
+                    tokio::fs::remove_file(&temp_file).await.at(&temp_file)?;
+                }
+                next_bucket += 1;
+                continue;
+            }
             
-            // Create a boxed reader that can handle both cases
-            let reader: Box<dyn AsyncBufRead + Unpin> = if input_file.extension().and_then(|s| s.to_str()) == Some("gz") {
-                Box::new(BufReader::new(GzipDecoder::new(buf_reader)))
-            } else {
-                Box::new(buf_reader)
+            let records = bucket_records[next_bucket];
+            let file_bytes = tokio::fs::metadata(&temp_file).await.at(&temp_file)?.len();
+            let bucket_kib = bucket::in_memory_size(file_bytes, records).div_ceil(1024);
+            let cost = bucket_kib.clamp(1, memory_budget_kib as u64) as u32;
+            let permit = match memory.clone().try_acquire_many_owned(cost) {
+                Ok(permit) => permit,
+                Err(_) if in_flight.is_empty() => memory.clone().acquire_many_owned(cost).await.map_err(io::Error::other)?,
+                Err(_) => break,
             };
             
-            readers.push(reader.lines());
+            let task_config = config.clone();
+            let num_buckets = temp_files.len();
+            let bucket_index = next_bucket;
+            in_flight.push_back((bucket_index, TaskGuard::spawn(tasks, async move {
+                let shuffled = shuffle_bucket_task(&task_config, &temp_file, records, bucket_index, num_buckets).await?;
+                Ok::<_, ShuffleError>((shuffled, permit))
+            }.in_current_span())));
+            next_bucket += 1;
         }
         
-        // Round-robin through readers in this batch
-        let mut active_readers = (0..readers.len()).collect::<Vec<_>>();
-        
-        while !active_readers.is_empty() {
-            let mut finished_readers = Vec::new();
-            
-            for (idx, &reader_idx) in active_readers.iter().enumerate() {
-                // Try to read a line from this reader
-                if let Some(line) = readers[reader_idx].next_line().await? {
-                    if !line.trim().is_empty() {
-                        // Randomly assign to one of the temp files
-                        let temp_index = rng.random_range(0..temp_files.len());
-                        line_buffer.add_line(temp_index, line);
-                        total_lines += 1;
-                        
-                        // Check if buffer is full
-                        if line_buffer.is_full(MAX_BUFFER_SIZE) {
-                            flush_line_buffer(&mut line_buffer, &temp_files, MAX_OPEN_OUTPUT_FILES, &config.delimiter).await?;
-                        }
+        // Consume buckets in order
+        let Some((bucket_index, task)) = in_flight.pop_front() else {
+            break;
+        };
+        let (shuffled, permit) = task.join().await??;
+        let temp_file = &temp_files[bucket_index];
+        let mut consumed = 0u64;
+        match shuffled {
+            ShuffledBucket::Written(written) => {
+                let empty = ShardDigest::default();
+                journal.record_written_bucket(first_bucket + bucket_index, written.first().map_or(&empty, |(_, shard)| shard)).await?;
+                for (path, shard) in written {
+                    debug!(records = shard.records, path = %path.display(), "Wrote output file");
+                    reporter.advance(0, shard.records as u64, temp_file);
+                    per_bucket_outputs.push((bucket_index, path, shard));
+                }
+                tokio::fs::remove_file(temp_file).await.at(temp_file)?;
+            }
+            ShuffledBucket::InMemory(records) => {
+                let shards = shards.as_mut().unwrap();
+                for record in &records {
+                    consumed += 1;
+                    if skip_records > 0 {
+                        skip_records -= 1;
+                        continue;
                     }
-                } else {
-                    // This reader is finished
-                    finished_readers.push(idx);
+                    shards.write_record(record).await?;
                 }
             }
-            
-            // Remove finished readers (in reverse order to maintain indices)
-            for &idx in finished_readers.iter().rev() {
-                active_readers.remove(idx);
+            ShuffledBucket::OnDisk(scratch) => {
+                let shards = shards.as_mut().unwrap();
+                let file = File::open(&scratch).await.at(&scratch)?;
+                let mut records = RecordReader::new(BufReader::new(file), &config.delimiter);
+                let sidecar = output::provenance_path(&scratch);
+                let mut origins = match output::scratch_sidecars(config) {
+                    true => Some(SidecarReader::open(&sidecar).await.at(&sidecar)?),
+                    false => None,
+                };
+                while let Some(line) = records.next_record().await.at(&scratch)? {
+                    // Without a sidecar the origin goes nowhere
+                    let (source, ordinal) = match origins.as_mut() {
+                        Some(origins) => origins.next_origin().await.at(&sidecar)?.ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::UnexpectedEof, "sidecar has fewer records than its scratch file")
+                        }).at(&sidecar)?,
+                        None => (0, 0),
+                    };
+                    consumed += 1;
+                    if skip_records > 0 {
+                        skip_records -= 1;
+                        continue;
+                    }
+                    shards.write_record(&TempRecord { key: 0, source, ordinal, copy: 0, line }).await?;
+                }
+                tokio::fs::remove_file(&scratch).await.at(&scratch)?;
+                if origins.is_some() {
+                    tokio::fs::remove_file(&sidecar).await.at(&sidecar)?;
+                }
             }
         }
+        drop(permit);
+        reporter.advance(bucket_bytes[bucket_index], consumed, temp_file);
+        
+        if let Some(shards) = shards.as_ref() {
+            stream_position += consumed;
+            journal.record_consumed_bucket(first_bucket + bucket_index, consumed).await?;
+            consumed_buckets.push_back((temp_file.clone(), stream_position));
+            let closed_records = journal_closed_shards(journal, set, shards.finished(), &mut journaled_shards).await?;
+            remove_consumed_buckets(&mut consumed_buckets, closed_records).await?;
+        }
     }
     
-    // Flush any remaining lines in the buffer
-    if !line_buffer.is_empty() {
-        flush_line_buffer(&mut line_buffer, &temp_files, MAX_OPEN_OUTPUT_FILES, &config.delimiter).await?;
+    if let Some(shards) = shards {
+        let written = shards.finish().await?;
+        let closed_records = journal_closed_shards(journal, set, &written, &mut journaled_shards).await?;
+        remove_consumed_buckets(&mut consumed_buckets, closed_records).await?;
+        for (path, shard) in written {
+            debug!(records = shard.records, path = %path.display(), "Wrote output file");
+            output_files.push(output_stats(config, epoch, split, path, shard));
+        }
     }
+    per_bucket_outputs.sort_by_key(|&(bucket_index, _, _)| bucket_index);
+    output_files.extend(per_bucket_outputs.into_iter().map(|(_, path, shard)| output_stats(config, epoch, split, path, shard)));
     
-    println!("Phase 1 complete: {} lines distributed across {} temp files", total_lines, temp_files.len());
+    info!(epoch, split = split.map(|split| split.name.as_str()), files = output_files.len(), "Phase 2 complete");
     
-    Ok(temp_files)
+    Ok(output_files)
 }
 
-async fn phase_2_shuffle_and_write(
+/// The config the shards of `split` in `epoch` are written with: with more
+/// than one epoch, `output_name` gains an `_epoch<e>` suffix, and a split's
+/// shards go in a subdirectory of `output_dir` named after it.
+fn set_config(config: &Arc<ShuffleConfig>, epoch: usize, split: Option<&Split>) -> Arc<ShuffleConfig> {
+    if config.epochs == 1 && split.is_none() {
+        return config.clone();
+    }
+    let mut set_config = config.as_ref().clone();
+    if config.epochs > 1 {
+        set_config.output_name = format!("{}_epoch{}", config.output_name, epoch);
+    }
+    if let Some(split) = split {
+        set_config.output_dir = config.output_dir.join(&split.name);
+    }
+    Arc::new(set_config)
+}
+
+fn output_stats(config: &ShuffleConfig, epoch: usize, split: Option<&Split>, path: PathBuf, shard: ShardDigest) -> OutputStats {
+    OutputStats {
+        epoch: (config.epochs > 1).then_some(epoch),
+        split: split.map(|split| split.name.clone()),
+        provenance: config.provenance.then(|| output::provenance_path(&path)),
+        path,
+        records: shard.records as u64,
+        bytes: shard.bytes,
+        sha256: shard.sha256,
+        group_records: config.source_groups.iter().map(|group| group.name.clone()).zip(shard.groups).collect(),
+    }
+}
+
+/// Journal shards closed since the last call and return how many records
+/// all closed shards hold.
+async fn journal_closed_shards(
+    journal: &mut Journal,
+    set: usize,
+    closed: &[(PathBuf, ShardDigest)],
+    journaled: &mut usize,
+) -> Result<u64, ShuffleError> {
+    for (index, (_, shard)) in closed.iter().enumerate().skip(*journaled) {
+        journal.record_closed_shard(set, index, shard).await?;
+    }
+    *journaled = closed.len();
+    Ok(closed.iter().map(|(_, shard)| shard.records as u64).sum())
+}
+
+/// Remove consumed temp buckets, oldest first, whose records all lie within
+/// the first `closed_records` records of the stream.
+async fn remove_consumed_buckets(consumed: &mut VecDeque<(PathBuf, u64)>, closed_records: u64) -> Result<(), ShuffleError> {
+    while let Some((temp_file, end)) = consumed.front() {
+        if *end > closed_records {
+            break;
+        }
+        tokio::fs::remove_file(temp_file).await.at(temp_file)?;
+        consumed.pop_front();
+    }
+    Ok(())
+}
+
+/// A bucket after shuffling, in final record order.
+enum ShuffledBucket {
+    /// Written straight to its own output shard (`ShardMode::PerBucket`)
+    Written(Vec<(PathBuf, ShardDigest)>),
+    /// Small enough to hand over in memory
+    InMemory(Vec<TempRecord>),
+    /// Too large for memory, spilled to an uncompressed scratch file
+    OnDisk(PathBuf),
+}
+
+/// Shuffle one temp bucket. The temp file is left for the caller to remove.
+async fn shuffle_bucket_task(
     config: &ShuffleConfig,
-    temp_files: Vec<PathBuf>,
-) -> Result<Vec<PathBuf>, io::Error> {
-    println!("Phase 2: Shuffling temp files and writing final output...");
+    temp_file: &Path,
+    records: u64,
+    bucket_index: usize,
+    num_buckets: usize,
+) -> Result<ShuffledBucket, ShuffleError> {
+    let key_range = KeyRange::FULL.part(bucket_index, num_buckets);
+    let bucket_size = tokio::fs::metadata(temp_file).await.at(temp_file)?.len();
+    let memory_budget = config.memory_budget_mb as u64 * 1024 * 1024;
     
-    let mut output_files = Vec::new();
-    let mut rng: Box<dyn RngCore> = match config.seed {
-        Some(seed) => Box::new(StdRng::seed_from_u64(seed.wrapping_add(1))), // Different seed for phase 2
-        None => Box::new(rng()),
+    let shuffled = if config.shard_mode == ShardMode::PerBucket {
+        let mut written = Vec::new();
+        // Skip empty temp files
+        if records > 0 {
+            let output_path = output::output_path(config, bucket_index, num_buckets > 1);
+            let mut shards = ShardWriter::new(config, ShardPlan::Single(output_path));
+            shuffle_bucket_into(config, temp_file, records, &mut shards, key_range, 0).await?;
+            written = shards.finish().await?;
+        }
+        ShuffledBucket::Written(written)
+    } else if bucket::in_memory_size(bucket_size, records) <= memory_budget {
+        ShuffledBucket::InMemory(read_sorted(temp_file, records).await.at(temp_file)?)
+    } else {
+        let mut name = temp_file.file_name().unwrap_or_default().to_os_string();
+        name.push(".shuffled");
+        let scratch = temp_file.with_file_name(name);
+        let mut shards = ShardWriter::new(config, ShardPlan::Scratch(scratch.clone()));
+        shuffle_bucket_into(config, temp_file, records, &mut shards, key_range, 0).await?;
+        shards.finish().await?;
+        ShuffledBucket::OnDisk(scratch)
     };
     
-    for (i, temp_file) in temp_files.iter().enumerate() {
-        // Read all lines from this temp file
-        let mut lines = Vec::new();
-        let file = File::open(temp_file).await?;
-        let reader = BufReader::new(file);
-        let mut line_stream = reader.lines();
-        
-        while let Some(line) = line_stream.next_line().await? {
-            if !line.trim().is_empty() {
-                lines.push(line);
-            }
-        }
-        
-        // Skip empty temp files
-        if lines.is_empty() {
-            continue;
+    Ok(shuffled)
+}
+
+/// Load every record of a bucket and sort them by key in memory, returning
+/// them in that order.
+///
+/// Keys are independent and uniform, so key order is a uniform shuffle.
+/// Ties (vanishingly rare, except within a group of records, which shares
+/// one key) fall back to input order so the result never depends on how
+/// phase 1 interleaved its writes. Duplicates of a record,
+/// left by a resumed phase 1 rereading part of an input, are dropped; the
+/// copies a mixture asks for are distinct records and are kept.
+async fn read_sorted(bucket: &Path, num_records: u64) -> Result<Vec<TempRecord>, io::Error> {
+    // Sized up front, as `bucket::in_memory_size` assumes
+    let mut records = Vec::with_capacity(num_records as usize);
+    let mut reader = bucket::open_bucket(bucket).await?;
+    while let Some(record) = reader.next_record().await? {
+        records.push(record);
+    }
+    records.sort_unstable_by_key(|r| (r.key, r.source, r.ordinal, r.copy));
+    records.dedup_by(|a, b| (a.source, a.ordinal, a.copy) == (b.source, b.ordinal, b.copy));
+    
+    Ok(records)
+}
+
+/// Shuffle the records of one bucket file, whose keys all fall in
+/// `key_range`, and append them to `shards`, returning how many records were
+/// written.
+///
+/// Buckets that fit in `memory_budget_mb` once loaded (see
+/// `bucket::in_memory_size`) are sorted in memory. Larger ones
+/// are scattered into sub-buckets covering consecutive slices of the key
+/// range, handled in turn and concatenated, recursing until every piece
+/// fits or holds a single key. The result is the same key order as if the
+/// bucket had fit.
+async fn shuffle_bucket_into(
+    config: &ShuffleConfig,
+    bucket: &Path,
+    records: u64,
+    shards: &mut ShardWriter<'_>,
+    key_range: KeyRange,
+    depth: usize,
+) -> Result<usize, ShuffleError> {
+    let bucket_size = tokio::fs::metadata(bucket).await.at(bucket)?.len();
+    let loaded_size = bucket::in_memory_size(bucket_size, records);
+    let memory_budget = config.memory_budget_mb as u64 * 1024 * 1024;
+    
+    // Records sharing a key (a group, when grouping) can't be split up, and
+    // past MAX_BUCKET_DEPTH we are splitting records that are individually huge
+    if loaded_size <= memory_budget || key_range.is_single_key() || depth >= MAX_BUCKET_DEPTH {
+        let records = read_sorted(bucket, records).await.at(bucket)?;
+        for record in &records {
+            shards.write_record(record).await?;
         }
         
-        // Shuffle the lines
-        lines.shuffle(&mut rng);
-        
-        // Write to final output file
-				let output_filename = if temp_files.len() == 1 {
-						format!("{}.{}", config.output_name, config.file_extension)
-				} else {
-						format!("{:04}_{}.{}", config.output_name, i + 1, config.file_extension)
-				};
-        
-        let output_path = config.output_dir.join(output_filename);
-        let output_file = File::create(&output_path).await?;
-        let mut writer = BufWriter::new(output_file);
-        
-				for line in &lines {
-						writer.write_all(line.as_bytes()).await?;
-						writer.write_all(config.delimiter.as_bytes()).await?;  // Use configured delimiter
-				}
-        
-        writer.flush().await?;
-        output_files.push(output_path.clone());
-        
-        println!("Wrote {} lines to {}", lines.len(), output_path.display());
-        
-        // Clean up temp file
-        tokio::fs::remove_file(temp_file).await?;
+        return Ok(records.len());
     }
     
-    println!("Phase 2 complete: {} final output files created", output_files.len());
+    // Twice the strict minimum so an unlucky split rarely needs another level
+    let num_sub_buckets = (loaded_size.div_ceil(memory_budget.max(1)) * 2).clamp(2, MAX_OPEN_OUTPUT_FILES as u64) as usize;
+    debug!(
+        bucket = %bucket.display(),
+        bytes = bucket_size,
+        loaded_bytes = loaded_size,
+        sub_buckets = num_sub_buckets,
+        "Bucket exceeds memory budget, splitting"
+    );
     
-    Ok(output_files)
+    let sub_buckets: Vec<PathBuf> = (0..num_sub_buckets)
+        .map(|j| {
+            let mut name = bucket.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{:03}", j));
+            bucket.with_file_name(name)
+        })
+        .collect();
+    
+    let mut sub_writers = Vec::with_capacity(num_sub_buckets);
+    for sub_bucket in &sub_buckets {
+        sub_writers.push(BufWriter::new(File::create(sub_bucket).await.at(sub_bucket)?));
+    }
+    
+    let mut sub_records = vec![0u64; num_sub_buckets];
+    // The lowest and highest key in each sub-bucket, which narrow its range
+    let mut sub_keys: Vec<Option<(u64, u64)>> = vec![None; num_sub_buckets];
+    let mut reader = bucket::open_bucket(bucket).await.at(bucket)?;
+    while let Some(record) = reader.next_record().await.at(bucket)? {
+        let sub_index = key_range.part_of(record.key, num_sub_buckets);
+        record.write_to(&mut sub_writers[sub_index]).await.at(&sub_buckets[sub_index])?;
+        sub_records[sub_index] += 1;
+        let (low, high) = sub_keys[sub_index].get_or_insert((record.key, record.key));
+        (*low, *high) = ((*low).min(record.key), (*high).max(record.key));
+    }
+    for (mut sub_writer, sub_bucket) in sub_writers.into_iter().zip(&sub_buckets) {
+        sub_writer.flush().await.at(sub_bucket)?;
+    }
+    
+    let mut written = 0;
+    for (j, sub_bucket) in sub_buckets.iter().enumerate() {
+        let sub_range = match sub_keys[j] {
+            Some((low, high)) => KeyRange::spanning(low, high),
+            None => key_range.part(j, num_sub_buckets),
+        };
+        written += Box::pin(shuffle_bucket_into(config, sub_bucket, sub_records[j], shards, sub_range, depth + 1)).await?;
+        tokio::fs::remove_file(sub_bucket).await.at(sub_bucket)?;
+    }
+    
+    Ok(written)
 }
 
-async fn estimate_total_input_size(input_files: &[PathBuf]) -> Result<usize, io::Error> {
+/// Estimate the decompressed size of all inputs, see
+/// `compression::estimate_uncompressed_size` for how each codec is handled.
+async fn estimate_total_input_size(input_files: &[PathBuf]) -> Result<usize, ShuffleError> {
     let mut total_size = 0;
     for file in input_files {
-        let metadata = tokio::fs::metadata(file).await?;
-        total_size += metadata.len() as usize;
+        total_size += compression::estimate_uncompressed_size(file)
+            .await
+            .map_err(|e| ShuffleError::opening_input(file, e))? as usize;
     }
     Ok(total_size)
-}
\ No newline at end of file
+}
+
+/// Records from the start of the inputs, read once to estimate how the
+/// whole of them behaves.
+struct RecordSample {
+    records: Vec<String>,
+    /// Bytes of the records, counting a delimiter after each
+    bytes: usize,
+}
+
+impl RecordSample {
+    /// Read the first `SAMPLE_SIZE` bytes of records, inputs in sorted order.
+    /// A missing input is an error, but one that can't be decoded just ends
+    /// the sample, leaving phase 1 to report exactly where it goes wrong.
+    async fn read(config: &ShuffleConfig) -> Result<Self, ShuffleError> {
+        const SAMPLE_SIZE: usize = 4 * 1024 * 1024;
+        
+        let mut sample = Self { records: Vec::new(), bytes: 0 };
+        let mut sorted_input_files = config.input_files.clone();
+        sorted_input_files.sort();
+        
+        for input_file in &sorted_input_files {
+            let reader = compression::open_input(input_file).await.map_err(|e| ShuffleError::opening_input(input_file, e))?;
+            let mut records = RecordReader::new(reader, &config.delimiter);
+            while let Ok(Some(record)) = records.next_record().await {
+                if sample.bytes >= SAMPLE_SIZE {
+                    return Ok(sample);
+                }
+                sample.bytes += record.len() + config.delimiter.len();
+                sample.records.push(record);
+            }
+        }
+        Ok(sample)
+    }
+    
+    /// Roughly how many records `bytes` of input hold, going by the
+    /// sample's average record size.
+    fn records_in(&self, bytes: u64) -> u64 {
+        match self.records.len() {
+            0 => 0,
+            records => (bytes as f64 * records as f64 / self.bytes as f64).ceil() as u64,
+        }
+    }
+    
+    /// Estimate compressed/uncompressed size for the output codec by
+    /// compressing the sample in shuffled order.
+    async fn compression_ratio(&self, config: &ShuffleConfig) -> Result<f64, ShuffleError> {
+        if self.records.is_empty() {
+            return Ok(1.0);
+        }
+        
+        // Neighbouring input records compress far better than shuffled output does
+        let mut records: Vec<&String> = self.records.iter().collect();
+        records.shuffle(&mut StdRng::seed_from_u64(0));
+        
+        let mut compressed = Vec::new();
+        let mut writer = compression::output_writer(
+            &mut compressed,
+            config.output_compression,
+            config.output_compression_level,
+        );
+        for record in records {
+            writer.write_all(record.as_bytes()).await?;
+            writer.write_all(config.delimiter.as_bytes()).await?;
+        }
+        writer.shutdown().await?;
+        drop(writer);
+        
+        Ok(compressed.len() as f64 / self.bytes as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_input(dir: &Path, name: &str, count: usize, padding: usize) -> PathBuf {
+        let path = dir.join(name);
+        let filler = "x".repeat(padding);
+        let content: String = (0..count)
+            .map(|i| format!("{{\"file\": \"{}\", \"id\": {}, \"pad\": \"{}\"}}\n", name, i, filler))
+            .collect();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Check the sizes and hashes a report gives for uncompressed shards.
+    fn assert_digests_match(report: &ShuffleReport) {
+        use sha2::Digest;
+        for output in &report.outputs {
+            let bytes = fs::read(&output.path).unwrap();
+            assert_eq!(output.bytes, bytes.len() as u64);
+            assert_eq!(output.sha256, format!("{:x}", sha2::Sha256::digest(&bytes)));
+            assert_eq!(output.records, bytes.iter().filter(|&&b| b == b'\n').count() as u64);
+        }
+    }
+
+    fn sorted_lines(paths: &[PathBuf]) -> Vec<String> {
+        let mut lines: Vec<String> = paths
+            .iter()
+            .flat_map(|p| fs::read_to_string(p).unwrap().lines().map(String::from).collect::<Vec<_>>())
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    fn test_config(inputs: Vec<PathBuf>, out_dir: &Path, max_size_mb: usize) -> ShuffleConfig {
+        ShuffleConfig::new(
+            inputs,
+            out_dir.to_str().unwrap(),
+            "shuffled",
+            max_size_mb,
+            "\n",
+            "jsonl",
+            Some(7),
+        ).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_oversized_bucket_is_split_and_stays_a_permutation() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 12_000, 200);
+        let out_dir = temp_dir.path().join("out");
+
+        let mut config = test_config(vec![input.clone()], &out_dir, 64);
+        config.memory_budget_mb = 1;
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(sorted_lines(&outputs), sorted_lines(&[input]));
+        // Only the output shard and the manifest are left behind
+        assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_temp_buckets_are_striped_across_temp_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 12_000, 200);
+        let out_dir = temp_dir.path().join("out");
+        let scratch_dirs = vec![temp_dir.path().join("scratch_a"), temp_dir.path().join("scratch_b")];
+
+        let mut config = test_config(vec![input.clone()], &out_dir, 64);
+        config.shard_mode = ShardMode::Count(1);
+        config.memory_budget_mb = 1;
+        config.temp_dirs = scratch_dirs.clone();
+
+        let temp_files = temp_file_paths(&config, 5);
+        for (i, temp_file) in temp_files.iter().enumerate() {
+            assert_eq!(temp_file.parent().unwrap(), scratch_dirs[i % 2]);
+        }
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+        assert_eq!(sorted_lines(&outputs), sorted_lines(&[input]));
+        assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 2);
+        for scratch_dir in &scratch_dirs {
+            assert_eq!(fs::read_dir(scratch_dir).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_free_space_check_rejects_huge_input() {
+        let temp_dir = TempDir::new().unwrap();
+        let dirs = vec![temp_dir.path().to_path_buf()];
+        check_free_space(&dirs, 4, 1024).unwrap();
+        if bucket::available_space(temp_dir.path()).is_some() {
+            let err = check_free_space(&dirs, 4, u64::MAX / 2).unwrap_err();
+            assert!(matches!(err, ShuffleError::StorageFull { path: Some(ref path), .. } if path == temp_dir.path()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_sample_estimates_record_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 10_000, 0);
+        let config = test_config(vec![input.clone()], temp_dir.path(), 64);
+        let sample = RecordSample::read(&config).await.unwrap();
+        let size = fs::metadata(&input).unwrap().len();
+        assert_eq!(sample.records_in(size), 10_000);
+        // Short records make the headers a large part of the temp files
+        assert!(10_000 * bucket::HEADER_LEN as u64 > size / 2);
+    }
+
+    #[test]
+    fn test_phase_1_buffers_fit_memory_budget() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = test_config(Vec::new(), temp_dir.path(), 64);
+        for (memory_budget_mb, concurrency, num_buckets) in [(64, 1, 1), (64, 16, 100), (4096, 64, 5000)] {
+            config.memory_budget_mb = memory_budget_mb;
+            config.concurrency = concurrency;
+            let (bucket_buffer_size, batch_size) = phase_1_buffer_sizes(&config, num_buckets).unwrap();
+            let used = bucket_buffer_size * num_buckets + batch_size * (3 * concurrency + 1);
+            assert!(used <= memory_budget_mb * 1024 * 1024, "{} MB budget, {} bytes used", memory_budget_mb, used);
+        }
+        // Buffers can't shrink below the minimum to fit any number of buckets
+        config.memory_budget_mb = 64;
+        assert!(matches!(phase_1_buffer_sizes(&config, 10_000), Err(ShuffleError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_shard_count_mode_balances_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 100, 0);
+        let out_dir = temp_dir.path().join("out");
+
+        let mut config = test_config(vec![input.clone()], &out_dir, 64);
+        config.shard_mode = ShardMode::Count(3);
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        let counts: Vec<usize> = outputs.iter().map(|p| fs::read_to_string(p).unwrap().lines().count()).collect();
+        assert_eq!(counts, vec![34, 33, 33]);
+        assert_eq!(sorted_lines(&outputs), sorted_lines(&[input]));
+    }
+
+    #[tokio::test]
+    async fn test_shard_count_mode_creates_empty_shards() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 2, 0);
+        let out_dir = temp_dir.path().join("out");
+
+        let mut config = test_config(vec![input], &out_dir, 64);
+        config.shard_mode = ShardMode::Count(4);
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        let counts: Vec<usize> = outputs.iter().map(|p| fs::read_to_string(p).unwrap().lines().count()).collect();
+        assert_eq!(counts, vec![1, 1, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_max_size_mode_bounds_every_shard() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 12_000, 200);
+        let out_dir = temp_dir.path().join("out");
+
+        let mut config = test_config(vec![input.clone()], &out_dir, 1);
+        config.shard_mode = ShardMode::MaxSize;
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        assert_eq!(outputs.len(), 3);
+        for output in &outputs {
+            assert!(fs::metadata(output).unwrap().len() <= 1024 * 1024);
+        }
+        assert_eq!(sorted_lines(&outputs), sorted_lines(&[input]));
+    }
+
+    #[tokio::test]
+    async fn test_max_size_mode_bounds_compressed_shards() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 12_000, 200);
+        let out_dir = temp_dir.path().join("out");
+
+        let mut config = test_config(vec![input], &out_dir, 1);
+        config.shard_mode = ShardMode::MaxSize;
+        config.output_compression = Compression::Lz4;
+        config.size_basis = SizeBasis::Compressed;
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        for output in &outputs {
+            assert!(fs::metadata(output).unwrap().len() <= 1024 * 1024);
+        }
+    }
+
+    fn read_outputs(paths: &[PathBuf]) -> Vec<(String, Vec<u8>)> {
+        paths
+            .iter()
+            .map(|p| (p.file_name().unwrap().to_string_lossy().to_string(), fs::read(p).unwrap()))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_output_does_not_depend_on_concurrency() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| write_input(temp_dir.path(), &format!("input_{}.jsonl", i), 4_000, 200))
+            .collect();
+
+        for shard_mode in [ShardMode::PerBucket, ShardMode::Count(2)] {
+            let mut runs = Vec::new();
+            for concurrency in [1, 4] {
+                let out_dir = temp_dir.path().join(format!("out_{}", concurrency));
+                let mut config = test_config(inputs.clone(), &out_dir, 1);
+                config.shard_mode = shard_mode;
+                config.concurrency = concurrency;
+                config.memory_budget_mb = 1;
+
+                let outputs = shuffle_files(&config).await.unwrap().output_paths();
+                runs.push(read_outputs(&outputs));
+                fs::remove_dir_all(&out_dir).unwrap();
+            }
+            assert!(runs[0].len() > 1);
+            assert_eq!(runs[0], runs[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_order_does_not_depend_on_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| write_input(temp_dir.path(), &format!("input_{}.jsonl", i), 3_000, 200))
+            .collect();
+        // The same files somewhere else, listed in a different order
+        let moved_dir = temp_dir.path().join("moved");
+        fs::create_dir(&moved_dir).unwrap();
+        let mut moved = Vec::new();
+        for input in inputs.iter().rev() {
+            let target = moved_dir.join(input.file_name().unwrap());
+            fs::copy(input, &target).unwrap();
+            moved.push(target);
+        }
+
+        // Bucket counts and splitting differ between these runs; the
+        // concatenated record order must not
+        let mut runs = Vec::new();
+        for (i, (inputs, memory_budget_mb, shard_mode)) in [
+            (inputs.clone(), 64, ShardMode::Count(1)),
+            (moved, 1, ShardMode::Count(3)),
+            (inputs, 1, ShardMode::PerBucket),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let out_dir = temp_dir.path().join(format!("out_{}", i));
+            let mut config = test_config(inputs, &out_dir, 1);
+            config.memory_budget_mb = memory_budget_mb;
+            config.shard_mode = shard_mode;
+
+            let outputs = shuffle_files(&config).await.unwrap().output_paths();
+            let lines: Vec<String> = outputs
+                .iter()
+                .flat_map(|p| fs::read_to_string(p).unwrap().lines().map(String::from).collect::<Vec<_>>())
+                .collect();
+            runs.push(lines);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert_eq!(runs[0], runs[2]);
+    }
+
+    /// Run phase 1 only, leaving temp files and a journal behind as a run
+    /// killed at the start of phase 2 would.
+    async fn interrupt_after_phase_1(config: &ShuffleConfig) -> PathBuf {
+        let config = Arc::new(config.clone());
+        let journal_path = journal::journal_path(&config);
+        let (_, num_buckets) = plan_buckets(&config, None).await.unwrap();
+        let fingerprint = journal::fingerprint(&config).await.unwrap();
+        let state = JournalState::new(fingerprint, config.seed.unwrap(), num_buckets);
+        let mut journal = Journal::create(&journal_path, &state).await.unwrap();
+        phase_1_distribute(&config, &state, &mut journal, None, None, &mut Reporter::distribute(&config, None), &mut JoinSet::new()).await.unwrap();
+        journal_path
+    }
+
+    fn resume_inputs(temp_dir: &Path) -> Vec<PathBuf> {
+        (0..3)
+            .map(|i| write_input(temp_dir, &format!("input_{}.jsonl", i), 4_000, 200))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_resume_phase_1_from_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs = resume_inputs(temp_dir.path());
+        let mut config = test_config(inputs, &temp_dir.path().join("reference"), 1);
+        config.memory_budget_mb = 1;
+        config.shard_mode = ShardMode::Count(2);
+        let expected = read_outputs(&shuffle_files(&config).await.unwrap().output_paths());
+
+        config.output_dir = temp_dir.path().join("resumed");
+        fs::create_dir(&config.output_dir).unwrap();
+        let journal_path = interrupt_after_phase_1(&config).await;
+
+        // Pretend the last input finished after the checkpoint, so its
+        // records are both in the buckets and read again, and that writes
+        // after the checkpoint left junk at the end of a bucket
+        let journal = fs::read_to_string(&journal_path).unwrap();
+        let mut lines: Vec<String> = journal.lines().filter(|l| !l.starts_with("phase1")).map(String::from).collect();
+        let checkpoint = lines.iter_mut().find(|l| l.starts_with("checkpoint")).unwrap();
+        checkpoint.truncate(checkpoint.rfind(',').unwrap());
+        fs::write(&journal_path, lines.join("\n") + "\n").unwrap();
+        let temp_file = temp_file_paths(&config, 1)[0].clone();
+        let mut bytes = fs::read(&temp_file).unwrap();
+        bytes.extend_from_slice(b"partial write");
+        fs::write(&temp_file, bytes).unwrap();
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+        assert_eq!(read_outputs(&outputs), expected);
+        assert_eq!(fs::read_dir(&config.output_dir).unwrap().count(), outputs.len() + 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume_phase_2_after_closed_shard() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs = resume_inputs(temp_dir.path());
+        let mut config = test_config(inputs, &temp_dir.path().join("out"), 1);
+        config.memory_budget_mb = 1;
+        config.shard_mode = ShardMode::Count(3);
+        let config = Arc::new(config);
+
+        // Save the temp files phase 1 leaves behind, then finish the run
+        // but keep its journal
+        let journal_path = interrupt_after_phase_1(&config).await;
+        let phase_1_journal = fs::read_to_string(&journal_path).unwrap();
+        let state = journal::read(&journal_path).await.unwrap().unwrap();
+        let temp_files = temp_file_paths(&config, state.num_buckets());
+        let saved = |path: &Path| temp_dir.path().join(path.file_name().unwrap());
+        for temp_file in &temp_files {
+            fs::copy(temp_file, saved(temp_file)).unwrap();
+        }
+        let mut journal = Journal::append(&journal_path).await.unwrap();
+        let mut reporter = Reporter::distribute(&config, None);
+        let distribution = phase_1_distribute(&config, &state, &mut journal, None, None, &mut reporter, &mut JoinSet::new()).await.unwrap();
+        let mut reporter = Reporter::shuffle(&config, 0, distribution.total_records());
+        let outputs = phase_2_shuffle_and_write(&config, &distribution, 0, &state, &mut journal, &mut reporter, &mut JoinSet::new()).await.unwrap();
+        let outputs: Vec<PathBuf> = outputs.into_iter().map(|output| output.path).collect();
+        let expected = read_outputs(&outputs);
+
+        // Rewind to just after the first shard was journaled as closed: the
+        // buckets it covers are gone and a later shard is half written
+        let full_journal = fs::read_to_string(&journal_path).unwrap();
+        let phase_2_lines: Vec<&str> = full_journal.lines().skip(phase_1_journal.lines().count()).collect();
+        let first_shard = phase_2_lines.iter().position(|l| l.starts_with("shard 0")).unwrap();
+        fs::write(&journal_path, format!("{}{}\n", phase_1_journal, phase_2_lines[..=first_shard].join("\n"))).unwrap();
+
+        let state = journal::read(&journal_path).await.unwrap().unwrap();
+        let closed: u64 = state.closed_shards[&0].iter().map(|shard| shard.records as u64).sum();
+        let mut covered = 0;
+        let mut removed = 0;
+        while let Some(&records) = state.consumed_buckets.get(&removed) {
+            if covered + records > closed {
+                break;
+            }
+            covered += records;
+            removed += 1;
+        }
+        assert!(removed > 0);
+        for temp_file in &temp_files[removed..] {
+            fs::copy(saved(temp_file), temp_file).unwrap();
+        }
+        fs::write(&outputs[1], "half written").unwrap();
+
+        let report = shuffle_files(&config).await.unwrap();
+        assert!(report.resumed);
+        assert_digests_match(&report);
+        let outputs = report.output_paths();
+        assert_eq!(read_outputs(&outputs), expected);
+        assert_eq!(fs::read_dir(&config.output_dir).unwrap().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_journal_from_other_run() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 100, 0);
+        let mut config = test_config(vec![input], &temp_dir.path().join("out"), 64);
+        interrupt_after_phase_1(&config).await;
+
+        config.seed = Some(8);
+        let err = shuffle_files(&config).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::JournalConflict { .. }));
+    }
+
+    /// Inputs whose second file only fails once phase 1 decodes it, after
+    /// the temp files exist.
+    fn failing_inputs(temp_dir: &Path) -> Vec<PathBuf> {
+        let broken = temp_dir.join("broken.jsonl.gz");
+        fs::write(&broken, "not gzip at all").unwrap();
+        vec![write_input(temp_dir, "input.jsonl", 1_000, 0), broken]
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_failed_run_removes_temp_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let temp_files_dir = temp_dir.path().join("temp");
+        let mut config = test_config(failing_inputs(temp_dir.path()), &temp_dir.path().join("out"), 64);
+        config.temp_dirs = vec![temp_files_dir.clone()];
+
+        let err = shuffle_files(&config).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::MalformedInput { .. }), "{}", err);
+        assert_eq!(dir_entries(&config.output_dir), Vec::<String>::new());
+        assert_eq!(dir_entries(&temp_files_dir), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_errors_say_what_went_wrong_where() {
+        let temp_dir = TempDir::new().unwrap();
+        let out_dir = temp_dir.path().join("out");
+        let input = write_input(temp_dir.path(), "input.jsonl", 10, 0);
+
+        let missing = temp_dir.path().join("missing.jsonl");
+        let config = test_config(vec![input.clone(), missing.clone()], &out_dir, 64);
+        let err = shuffle_files(&config).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::InputNotFound { ref path } if *path == missing));
+
+        // Invalid UTF-8 in the fourth record
+        let invalid = temp_dir.path().join("invalid.jsonl");
+        fs::write(&invalid, b"{}\n{}\n{}\n\xff\n{}\n").unwrap();
+        let config = test_config(vec![input.clone(), invalid.clone()], &out_dir, 64);
+        let err = shuffle_files(&config).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::MalformedInput { ref path, record: 3, .. } if *path == invalid));
+
+        let mut config = test_config(vec![input.clone()], &out_dir, 64);
+        config.concurrency = 0;
+        assert!(matches!(shuffle_files(&config).await.unwrap_err(), ShuffleError::InvalidConfig(_)));
+
+        // No record fits in a shard of 0 MB
+        let mut config = test_config(vec![input], &out_dir, 0);
+        config.shard_mode = ShardMode::MaxSize;
+        let err = shuffle_files(&config).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::RecordTooLarge { limit: 0, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_failed_run_keeps_temp_files_on_request() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = test_config(failing_inputs(temp_dir.path()), &temp_dir.path().join("out"), 64);
+        config.keep_temp_files = true;
+
+        assert!(shuffle_files(&config).await.is_err());
+        let entries = dir_entries(&config.output_dir);
+        assert!(entries.contains(&".shuffled_journal".to_string()));
+        assert!(entries.iter().any(|name| name.starts_with(".shuffled_temp_")));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_run_cleans_up() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 1_000, 0);
+        let config = test_config(vec![input], &temp_dir.path().join("out"), 64);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = shuffle_files_with_cancel(&config, &cancel).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::Cancelled));
+        assert_eq!(dir_entries(&config.output_dir), Vec::<String>::new());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_run_cancelled_in_phase_2_leaves_no_temp_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| write_input(temp_dir.path(), &format!("input_{}.jsonl", i), 4_000, 200))
+            .collect();
+        let mut config = test_config(inputs, &temp_dir.path().join("out"), 1);
+        config.concurrency = 4;
+        config.memory_budget_mb = 1;
+
+        // Cancel as soon as the first shard is being written
+        let cancel = CancellationToken::new();
+        let watcher = tokio::spawn({
+            let (cancel, out_dir) = (cancel.clone(), config.output_dir.clone());
+            async move {
+                while !dir_entries(&out_dir).iter().any(|name| name.ends_with(".partial")) {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                cancel.cancel();
+            }
+        });
+        let err = shuffle_files_with_cancel(&config, &cancel).await.unwrap_err();
+        assert!(matches!(err, ShuffleError::Cancelled));
+        watcher.await.unwrap();
+
+        // Nothing turns up later either
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let left: Vec<String> = dir_entries(&config.output_dir)
+            .into_iter()
+            .filter(|name| name.starts_with(".shuffled_") || name.ends_with(".partial"))
+            .collect();
+        assert_eq!(left, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_shards_appear_only_when_complete() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = test_config(Vec::new(), temp_dir.path(), 64);
+
+        let mut shards = ShardWriter::new(&config, ShardPlan::Counts(vec![1, 1]));
+        shards.write_record(&TempRecord { key: 0, source: 0, ordinal: 0, copy: 0, line: "first".to_string() }).await.unwrap();
+        assert_eq!(dir_entries(temp_dir.path()), vec![".shuffled_1.jsonl.partial"]);
+        shards.write_record(&TempRecord { key: 0, source: 0, ordinal: 1, copy: 0, line: "second".to_string() }).await.unwrap();
+        assert_eq!(dir_entries(temp_dir.path()), vec![".shuffled_2.jsonl.partial", "shuffled_1.jsonl"]);
+
+        // An abandoned shard never shows up under its final name
+        drop(shards);
+        assert_eq!(dir_entries(temp_dir.path()), vec!["shuffled_1.jsonl"]);
+    }
+
+    #[tokio::test]
+    async fn test_report_describes_run_and_matches_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = write_input(temp_dir.path(), "input.jsonl", 100, 0);
+        let with_blanks = temp_dir.path().join("blanks.jsonl");
+        fs::write(&with_blanks, "{\"a\": 1}\n\n   \n{\"a\": 2}\n").unwrap();
+        let mut config = test_config(vec![input.clone(), with_blanks.clone()], &temp_dir.path().join("out"), 64);
+        config.shard_mode = ShardMode::Count(3);
+
+        let report = shuffle_files(&config).await.unwrap();
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.seed, 7);
+        assert!(!report.resumed);
+        assert_eq!(report.config.shard_mode, ShardMode::Count(3));
+        let size = |path: &Path| fs::metadata(path).unwrap().len();
+        let inputs: Vec<_> = report.inputs.iter().map(|i| (i.path.clone(), i.records, i.bytes, i.blank_records)).collect();
+        assert_eq!(inputs, vec![
+            (with_blanks.clone(), 2, size(&with_blanks), 2),
+            (input.clone(), 100, size(&input), 0),
+        ]);
+        assert_eq!(report.outputs.len(), 3);
+        assert_eq!(report.records(), 102);
+        assert_digests_match(&report);
+        assert_eq!(ShuffleReport::read(&report::manifest_path(&config)).await.unwrap(), report);
+    }
+
+    #[tokio::test]
+    async fn test_epochs_match_single_runs_with_their_seeds() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs: Vec<PathBuf> = (0..2)
+            .map(|i| write_input(temp_dir.path(), &format!("input_{}.jsonl", i), 1_500, 300))
+            .collect();
+        let mut config = test_config(inputs.clone(), &temp_dir.path().join("out"), 64);
+        config.shard_mode = ShardMode::Count(2);
+        config.memory_budget_mb = 1;
+        config.epochs = 3;
+        let report = shuffle_files(&config).await.unwrap();
+        assert_eq!(report.records(), 3 * 3_000);
+        assert_eq!(report.epoch_seeds[0], 7);
+
+        // Each epoch is what a single-epoch run with its seed writes
+        let mut epochs = Vec::new();
+        for (epoch, &seed) in report.epoch_seeds.iter().enumerate() {
+            let name = format!("shuffled_epoch{}", epoch);
+            let mut single = ShuffleConfig::new(inputs.clone(), temp_dir.path().join("single").to_str().unwrap(), &name, 64, "\n", "jsonl", Some(seed)).unwrap();
+            single.shard_mode = ShardMode::Count(2);
+            let expected = shuffle_files(&single).await.unwrap().output_paths();
+            let paths = report.epoch_paths(epoch);
+            assert_eq!(read_outputs(&paths), read_outputs(&expected));
+            assert_eq!(sorted_lines(&paths), sorted_lines(&inputs));
+            epochs.push(read_outputs(&paths));
+        }
+        assert_ne!(epochs[0][0].1, epochs[1][0].1);
+        assert_ne!(epochs[1][0].1, epochs[2][0].1);
+
+        assert!(crate::verify_manifest(&report::manifest_path(&config), 2).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_progress_observer_sees_both_phases() {
+        let temp_dir = TempDir::new().unwrap();
+        let inputs = vec![
+            write_input(temp_dir.path(), "a.jsonl", 3_000, 20),
+            write_input(temp_dir.path(), "b.jsonl", 2_000, 20),
+        ];
+        let input_bytes: u64 = inputs.iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+        let mut config = test_config(inputs.clone(), &temp_dir.path().join("out"), 64);
+        config.shard_mode = ShardMode::Count(2);
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        config.progress = Some(Arc::new(move |progress: &crate::Progress| sink.lock().unwrap().push(progress.clone())));
+
+        shuffle_files(&config).await.unwrap().output_paths();
+
+        let reports = reports.lock().unwrap();
+        let (distribute, shuffle): (Vec<_>, Vec<_>) = reports.iter().partition(|p| p.phase == Phase::Distribute);
+        assert!(reports[..distribute.len()].iter().all(|p| p.phase == Phase::Distribute));
+
+        // Each phase is reported from its start to its end
+        assert_eq!((distribute[0].bytes, distribute[0].records), (0, 0));
+        let last = distribute.last().unwrap();
+        assert_eq!((last.bytes, last.total_bytes, last.records), (input_bytes, Some(input_bytes), 5_000));
+        assert!(inputs.contains(last.current_file.as_ref().unwrap()));
+
+        assert_eq!((shuffle[0].bytes, shuffle[0].records), (0, 0));
+        let last = shuffle.last().unwrap();
+        assert_eq!((last.records, last.total_records), (5_000, Some(5_000)));
+        assert_eq!(Some(last.bytes), last.total_bytes);
+    }
+
+    /// Compare against the pinned outputs in `data/golden`, or rewrite them
+    /// when `SHUFFLY_BLESS` is set. These must only change together with a
+    /// deliberate, documented change to the shuffling scheme.
+    async fn check_golden(name: &str, shard_mode: ShardMode, concurrency: usize, reverse_inputs: bool) {
+        let data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+        let mut inputs: Vec<PathBuf> = ["bar.jsonl", "baz.jsonl", "foo.jsonl"].iter().map(|f| data_dir.join(f)).collect();
+        if reverse_inputs {
+            inputs.reverse();
+        }
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = test_config(inputs, temp_dir.path(), 64);
+        config.seed = Some(42);
+        config.shard_mode = shard_mode;
+        config.concurrency = concurrency;
+
+        let outputs = shuffle_files(&config).await.unwrap().output_paths();
+
+        let golden_dir = data_dir.join("golden").join(name);
+        if std::env::var_os("SHUFFLY_BLESS").is_some() {
+            fs::create_dir_all(&golden_dir).unwrap();
+            for output in &outputs {
+                fs::copy(output, golden_dir.join(output.file_name().unwrap())).unwrap();
+            }
+        }
+        let mut expected: Vec<PathBuf> = fs::read_dir(&golden_dir).unwrap().map(|e| e.unwrap().path()).collect();
+        expected.sort();
+        assert_eq!(read_outputs(&outputs), read_outputs(&expected), "output differs from {}", golden_dir.display());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_golden_single_shard() {
+        check_golden("single", ShardMode::PerBucket, 1, false).await;
+        check_golden("single", ShardMode::PerBucket, 4, true).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_golden_three_shards() {
+        check_golden("three_shards", ShardMode::Count(3), 1, true).await;
+        check_golden("three_shards", ShardMode::Count(3), 4, false).await;
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::io;
+use serde::{Deserialize, Serialize};
+use crate::error::ShuffleError;
+use crate::grouping;
+use crate::mixing;
+use crate::seeding;
+use crate::shuffle::{ShardMode, ShuffleConfig};
+
+/// Stream identifier for assigning records to splits
+const SPLIT_STREAM: u64 = 4;
+
+/// A named share of the records, written to its own shard set in a
+/// subdirectory of `output_dir` with the split's name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Split {
+    pub name: String,
+    /// Share of the records, relative to the other splits' (so 98, 1, 1
+    /// and 0.98, 0.01, 0.01 are the same)
+    pub fraction: f64,
+}
+
+/// Check the splits and split key of a config.
+pub(crate) fn validate(config: &ShuffleConfig) -> Result<(), ShuffleError> {
+    let invalid = |message: String| Err(ShuffleError::InvalidConfig(message));
+    if config.splits.is_empty() {
+        return match &config.split_key {
+            Some(_) => invalid("split_key needs splits".to_string()),
+            None => Ok(()),
+        };
+    }
+    let mut names = HashSet::new();
+    for split in &config.splits {
+        let usable = !split.name.is_empty() && split.name != "." && split.name != ".." && !split.name.contains(['/', '\\']);
+        if !usable || !names.insert(split.name.as_str()) {
+            return invalid(format!("split names must be unique directory names, got '{}'", split.name));
+        }
+        if !(split.fraction.is_finite() && split.fraction > 0.0) {
+            return invalid(format!("split '{}' needs a positive fraction, got {}", split.name, split.fraction));
+        }
+    }
+    if let ShardMode::Count(num_shards) = config.shard_mode {
+        if num_shards < config.splits.len() {
+            return invalid(format!("{} shards can't give each of {} splits one", num_shards, config.splits.len()));
+        }
+    }
+    match &config.split_key {
+        Some(key) if grouping::field_path(key).is_none() => invalid(format!("bad split key '{}'", key)),
+        _ => Ok(()),
+    }
+}
+
+/// Each split's share of the records, in order; a single share of 1 without
+/// splits.
+pub(crate) fn shares(config: &ShuffleConfig) -> Vec<f64> {
+    let total: f64 = config.splits.iter().map(|split| split.fraction).sum();
+    match config.splits.len() {
+        0 => vec![1.0],
+        _ => config.splits.iter().map(|split| split.fraction / total).collect(),
+    }
+}
+
+/// Each split's share of `num_shards` shards, in order: by fraction, rounded
+/// by largest remainder so they add up, with at least one each.
+pub(crate) fn shard_counts(config: &ShuffleConfig, num_shards: usize) -> Vec<usize> {
+    let mut counts: Vec<usize> = mixing::targets(&shares(config), num_shards as u64).into_iter().map(|count| count as usize).collect();
+    // A split too small for a shard of its own takes one from the largest
+    while let Some(empty) = counts.iter().position(|&count| count == 0) {
+        let largest = (0..counts.len()).max_by_key(|&index| counts[index]).unwrap();
+        counts[largest] -= 1;
+        counts[empty] = 1;
+    }
+    counts
+}
+
+/// Assigns records to the splits of a config.
+///
+/// Every record draws a 64-bit value and lands in the split whose slice of
+/// the value range, sized by its fraction, holds it. The value is
+/// `derive_seed(seed, [4, source, ordinal])` for the record's input identity
+/// and position (or its group's hash, when grouping), or, with a split key,
+/// a hash of the key's value that doesn't depend on the seed or the
+/// record's position at all, so a document keeps its split across reruns
+/// and data refreshes.
+#[derive(Debug, Clone)]
+pub(crate) struct Splitter {
+    /// Upper end (inclusive) of each split's slice of the value range
+    bounds: Vec<u64>,
+    key: Option<Vec<String>>,
+    seed: u64,
+}
+
+impl Splitter {
+    /// The splitter of a validated config, which puts every record in
+    /// split 0 if it has no splits.
+    pub(crate) fn new(config: &ShuffleConfig, seed: u64) -> Self {
+        let total: f64 = config.splits.iter().map(|split| split.fraction).sum();
+        let mut cumulative = 0.0;
+        let mut bounds: Vec<u64> = config
+            .splits
+            .iter()
+            .map(|split| {
+                cumulative += split.fraction;
+                (cumulative / total * u64::MAX as f64) as u64
+            })
+            .collect();
+        // The last split takes whatever rounding left over
+        match bounds.last_mut() {
+            Some(last) => *last = u64::MAX,
+            None => bounds.push(u64::MAX),
+        }
+        Self {
+            bounds,
+            key: config.split_key.as_deref().and_then(grouping::field_path),
+            seed: seeding::derive_seed(seed, &[SPLIT_STREAM]),
+        }
+    }
+
+    /// Split of a non-blank record, given where it is from: its input's
+    /// identity and its position in the input, or its group's hash. Fails if
+    /// a split key is set and the record is not a JSON object with that
+    /// field.
+    pub(crate) fn assign(&self, record: &str, origin: &[u64]) -> Result<usize, io::Error> {
+        let value = match &self.key {
+            Some(key) => seeding::derive_seed(grouping::field_hash(record, key)?, &[SPLIT_STREAM]),
+            None => seeding::derive_seed(self.seed, origin),
+        };
+        Ok(self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len() - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+    use crate::report::ShuffleReport;
+    use crate::shuffle::{shuffle_files, ShardMode};
+
+    fn config_with_splits(splits: &[(&str, f64)], split_key: Option<&str>) -> ShuffleConfig {
+        let mut config = ShuffleConfig::new(Vec::new(), std::env::temp_dir().to_str().unwrap(), "split", 64, "\n", "jsonl", None).unwrap();
+        config.splits = splits.iter().map(|&(name, fraction)| Split { name: name.to_string(), fraction }).collect();
+        config.split_key = split_key.map(String::from);
+        config
+    }
+
+    #[test]
+    fn test_assigns_by_fraction_and_key() {
+        let config = config_with_splits(&[("train", 98.0), ("val", 1.0), ("test", 1.0)], None);
+        let splitter = Splitter::new(&config, 7);
+        let mut counts = [0; 3];
+        for ordinal in 0..100_000 {
+            counts[splitter.assign("{}", &[42, ordinal]).unwrap()] += 1;
+        }
+        assert!((97_500..98_500).contains(&counts[0]), "{:?}", counts);
+        assert!((800..1_200).contains(&counts[1]) && (800..1_200).contains(&counts[2]), "{:?}", counts);
+
+        // A keyed split ignores the seed and where the record is
+        let config = config_with_splits(&[("train", 0.5), ("test", 0.5)], Some("meta.id"));
+        let (a, b) = (Splitter::new(&config, 1), Splitter::new(&config, 2));
+        for id in 0..100 {
+            let record = format!("{{\"meta\": {{\"id\": \"doc-{}\"}}, \"text\": \"v1\"}}", id);
+            let refreshed = format!("{{\"text\": \"v2\", \"meta\": {{\"id\": \"doc-{}\"}}}}", id);
+            assert_eq!(a.assign(&record, &[1, id]).unwrap(), b.assign(&refreshed, &[9, 1000 - id]).unwrap());
+        }
+        assert!(a.assign("{\"meta\": {}}", &[0, 0]).is_err());
+        assert!(a.assign("not json", &[0, 0]).is_err());
+
+        assert!(validate(&config_with_splits(&[("a", 1.0), ("a", 1.0)], None)).is_err());
+        assert!(validate(&config_with_splits(&[("../a", 1.0)], None)).is_err());
+        assert!(validate(&config_with_splits(&[("a", 0.0)], None)).is_err());
+        assert!(validate(&config_with_splits(&[], Some("id"))).is_err());
+        let mut config = config_with_splits(&[("a", 1.0), ("b", 1.0)], None);
+        config.shard_mode = ShardMode::Count(1);
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn test_shard_counts_add_up() {
+        let halves = config_with_splits(&[("a", 0.5), ("b", 0.5)], None);
+        assert_eq!(shard_counts(&halves, 3), vec![2, 1]);
+        assert_eq!(shard_counts(&halves, 2), vec![1, 1]);
+        let skewed = config_with_splits(&[("train", 98.0), ("val", 1.0), ("test", 1.0)], None);
+        assert_eq!(shard_counts(&skewed, 10), vec![8, 1, 1]);
+        assert_eq!(shard_counts(&skewed, 100), vec![98, 1, 1]);
+        assert_eq!(shard_counts(&skewed, 3), vec![1, 1, 1]);
+        for num_shards in 3..50 {
+            assert_eq!(shard_counts(&skewed, num_shards).iter().sum::<usize>(), num_shards);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_splits_partition_the_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = temp_dir.path().join("input.jsonl");
+        let content: String = (0..2_000).map(|i| format!("{{\"id\": {}}}\n", i)).collect();
+        fs::write(&input, content).unwrap();
+        let out_dir = temp_dir.path().join("out");
+        let mut config = ShuffleConfig::new(vec![input], out_dir.to_str().unwrap(), "data", 64, "\n", "jsonl", Some(3)).unwrap();
+        config.splits = vec![Split { name: "train".to_string(), fraction: 0.8 }, Split { name: "val".to_string(), fraction: 0.2 }];
+        config.split_key = Some("id".to_string());
+        config.shard_mode = ShardMode::Count(5);
+        let report = shuffle_files(&config).await.unwrap();
+
+        let lines = |report: &ShuffleReport, out_dir: &Path, split: &str| -> Vec<String> {
+            let paths: Vec<PathBuf> =
+                report.outputs.iter().filter(|output| output.split.as_deref() == Some(split)).map(|output| output.path.clone()).collect();
+            assert!(paths.iter().all(|path| path.starts_with(out_dir.join(split))));
+            // Shards are shared out by fraction
+            assert_eq!(paths.len(), if split == "train" { 4 } else { 1 });
+            let mut lines: Vec<String> = paths.iter().flat_map(|path| fs::read_to_string(path).unwrap().lines().map(String::from).collect::<Vec<_>>()).collect();
+            lines.sort();
+            lines
+        };
+        let (train, val) = (lines(&report, &out_dir, "train"), lines(&report, &out_dir, "val"));
+        assert_eq!(train.len() + val.len(), 2_000);
+        assert!((300..500).contains(&val.len()), "{} val records", val.len());
+        assert!(train.iter().all(|line| val.binary_search(line).is_err()));
+        let summary: Vec<(&str, u64)> = report.splits.iter().map(|split| (split.name.as_str(), split.records)).collect();
+        assert_eq!(summary, vec![("train", train.len() as u64), ("val", val.len() as u64)]);
+
+        // Another seed reorders each split but keeps its records
+        config.seed = Some(4);
+        config.output_dir = temp_dir.path().join("reseeded");
+        let reseeded = shuffle_files(&config).await.unwrap();
+        assert_eq!(lines(&reseeded, &config.output_dir, "val"), val);
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+use crate::compression::{self, Compression};
+use crate::error::ShuffleError;
+use crate::records::RecordReader;
+use crate::report::ShuffleReport;
+
+/// Order-independent digest of a collection of records: the same for every
+/// ordering of the same records, and (with overwhelming probability)
+/// different once a record is lost, duplicated or altered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordDigest {
+    pub records: u64,
+    /// Sum modulo 2^128 of the first 128 bits of each record's SHA-256
+    pub hash: u128,
+}
+
+impl RecordDigest {
+    fn add(&mut self, record: &str) {
+        let sha256 = Sha256::digest(record.as_bytes());
+        let mut prefix = [0u8; 16];
+        prefix.copy_from_slice(&sha256[..16]);
+        self.records += 1;
+        self.hash = self.hash.wrapping_add(u128::from_be_bytes(prefix));
+    }
+
+    fn merge(&mut self, other: &RecordDigest) {
+        self.records += other.records;
+        self.hash = self.hash.wrapping_add(other.hash);
+    }
+}
+
+impl fmt::Display for RecordDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} records, hash {:032x}", self.records, self.hash)
+    }
+}
+
+/// What `verify` found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// The inputs' non-blank records
+    pub inputs: RecordDigest,
+    /// Blank input records, which a shuffle drops
+    pub blank_records: u64,
+    /// The outputs' records, over all epochs
+    pub outputs: RecordDigest,
+    /// Every difference found; empty if the outputs are a permutation of
+    /// the inputs (and match the manifest, if one was used)
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A difference `verify` found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The outputs hold a different number of records than the inputs
+    RecordCount { inputs: u64, outputs: u64 },
+    /// The outputs hold as many records as the inputs, but not the same ones
+    Records,
+    /// A file holds a different number of records than the manifest says
+    FileRecords { path: PathBuf, expected: u64, found: u64 },
+    /// An input holds a different number of blank records than the manifest says
+    BlankRecords { path: PathBuf, expected: u64, found: u64 },
+    /// An output's size or SHA-256 differs from the manifest's
+    FileChecksum { path: PathBuf },
+    /// One epoch's outputs of a multi-epoch run differ from the inputs
+    Epoch { epoch: usize, mismatch: Box<Mismatch> },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::RecordCount { inputs, outputs } => {
+                write!(f, "inputs hold {} records but outputs hold {}", inputs, outputs)
+            }
+            Mismatch::Records => f.write_str("outputs hold as many records as the inputs, but not the same ones"),
+            Mismatch::FileRecords { path, expected, found } => {
+                write!(f, "{} holds {} records, the manifest says {}", path.display(), found, expected)
+            }
+            Mismatch::BlankRecords { path, expected, found } => {
+                write!(f, "{} holds {} blank records, the manifest says {}", path.display(), found, expected)
+            }
+            Mismatch::FileChecksum { path } => write!(f, "{} differs from the manifest's size or SHA-256", path.display()),
+            Mismatch::Epoch { epoch, mismatch } => write!(f, "epoch {}: {}", epoch, mismatch),
+        }
+    }
+}
+
+/// Check that `outputs` hold exactly the non-blank records of `inputs`,
+/// each as many times, in any order.
+///
+/// Both sides are read in full (decompressing as `shuffle_files` does) and
+/// reduced to a `RecordDigest`, reading up to `concurrency` files at once.
+pub async fn verify(
+    inputs: &[PathBuf],
+    outputs: &[PathBuf],
+    delimiter: &str,
+    concurrency: usize,
+) -> Result<Verification, ShuffleError> {
+    let (verification, _, _) = scan_both(inputs, outputs, 1, &[], delimiter, concurrency).await?;
+    Ok(verification)
+}
+
+/// Like `verify`, for the inputs and outputs listed in the manifest at
+/// `manifest`, also checking every file's record counts and every output's
+/// size and SHA-256 against it.
+///
+/// Relative paths in the manifest are taken as they were written, relative
+/// to the directory the shuffle ran in. Each epoch of a multi-epoch run must
+/// hold the inputs' records on its own. Runs that mixed source groups or
+/// repeated inputs can't be verified.
+pub async fn verify_manifest(manifest: &Path, concurrency: usize) -> Result<Verification, ShuffleError> {
+    let report = ShuffleReport::read(manifest).await?;
+    if !report.config.source_groups.is_empty() || !report.config.repeats.is_empty() {
+        return Err(ShuffleError::InvalidConfig(
+            "a mixed or repeated run repeats and drops records, so its outputs aren't a permutation of its inputs"
+                .to_string(),
+        ));
+    }
+    let inputs: Vec<PathBuf> = report.inputs.iter().map(|input| input.path.clone()).collect();
+    let epochs: Vec<usize> = report.outputs.iter().map(|output| output.epoch.unwrap_or(0)).collect();
+    let (mut verification, input_scans, output_scans) =
+        scan_both(&inputs, &report.output_paths(), report.config.epochs, &epochs, &report.config.delimiter, concurrency)
+            .await?;
+
+    let mut mismatches = Vec::new();
+    for (input, scan) in report.inputs.iter().zip(&input_scans) {
+        if scan.digest.records != input.records {
+            let (expected, found) = (input.records, scan.digest.records);
+            mismatches.push(Mismatch::FileRecords { path: input.path.clone(), expected, found });
+        }
+        if scan.blank_records != input.blank_records {
+            let (expected, found) = (input.blank_records, scan.blank_records);
+            mismatches.push(Mismatch::BlankRecords { path: input.path.clone(), expected, found });
+        }
+    }
+    for (output, scan) in report.outputs.iter().zip(&output_scans) {
+        if scan.digest.records != output.records {
+            let (expected, found) = (output.records, scan.digest.records);
+            mismatches.push(Mismatch::FileRecords { path: output.path.clone(), expected, found });
+        }
+        if scan.bytes != output.bytes || scan.sha256 != output.sha256 {
+            mismatches.push(Mismatch::FileChecksum { path: output.path.clone() });
+        }
+    }
+    // Per-file problems explain the overall ones, so they come first
+    mismatches.append(&mut verification.mismatches);
+    verification.mismatches = mismatches;
+    Ok(verification)
+}
+
+/// Scan `inputs` and `outputs` and compare their records, separately for
+/// each of `num_epochs` epochs when `epochs` gives the epoch of every output.
+async fn scan_both(
+    inputs: &[PathBuf],
+    outputs: &[PathBuf],
+    num_epochs: usize,
+    epochs: &[usize],
+    delimiter: &str,
+    concurrency: usize,
+) -> Result<(Verification, Vec<FileScan>, Vec<FileScan>), ShuffleError> {
+    if delimiter.is_empty() {
+        return Err(ShuffleError::InvalidConfig("delimiter must not be empty".to_string()));
+    }
+    if concurrency == 0 {
+        return Err(ShuffleError::InvalidConfig("concurrency must be at least 1".to_string()));
+    }
+
+    info!(inputs = inputs.len(), outputs = outputs.len(), "Verifying");
+    let input_scans = scan_files(inputs, delimiter, true, concurrency).await?;
+    let output_scans = scan_files(outputs, delimiter, false, concurrency).await?;
+
+    let mut verification = Verification {
+        inputs: RecordDigest::default(),
+        blank_records: 0,
+        outputs: RecordDigest::default(),
+        mismatches: Vec::new(),
+    };
+    for scan in &input_scans {
+        verification.inputs.merge(&scan.digest);
+        verification.blank_records += scan.blank_records;
+    }
+    let mut epoch_outputs = vec![RecordDigest::default(); num_epochs];
+    for (index, scan) in output_scans.iter().enumerate() {
+        verification.outputs.merge(&scan.digest);
+        epoch_outputs[epochs.get(index).copied().unwrap_or(0)].merge(&scan.digest);
+    }
+    let inputs = verification.inputs;
+    for (epoch, outputs) in epoch_outputs.into_iter().enumerate() {
+        let mismatch = if inputs.records != outputs.records {
+            Mismatch::RecordCount { inputs: inputs.records, outputs: outputs.records }
+        } else if inputs.hash != outputs.hash {
+            Mismatch::Records
+        } else {
+            continue;
+        };
+        verification.mismatches.push(match num_epochs {
+            1 => mismatch,
+            _ => Mismatch::Epoch { epoch, mismatch: Box::new(mismatch) },
+        });
+    }
+    info!(inputs = %inputs, outputs = %verification.outputs, mismatches = verification.mismatches.len(), "Verified");
+    Ok((verification, input_scans, output_scans))
+}
+
+/// What reading one file found.
+struct FileScan {
+    digest: RecordDigest,
+    blank_records: u64,
+    /// Size of the file on disk
+    bytes: u64,
+    /// Hex SHA-256 of the file on disk
+    sha256: String,
+}
+
+/// Scan `paths` with up to `concurrency` workers, each claiming the next
+/// unscanned file. Results are in the order of `paths`.
+async fn scan_files(
+    paths: &[PathBuf],
+    delimiter: &str,
+    skip_blank: bool,
+    concurrency: usize,
+) -> Result<Vec<FileScan>, ShuffleError> {
+    let paths = Arc::new(paths.to_vec());
+    let next = Arc::new(AtomicUsize::new(0));
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency.min(paths.len()) {
+        let (paths, next, delimiter) = (paths.clone(), next.clone(), delimiter.to_string());
+        workers.spawn(async move {
+            let mut scans = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                scans.push((index, scan_file(path, &delimiter, skip_blank).await?));
+            }
+            Ok::<_, ShuffleError>(scans)
+        });
+    }
+
+    let mut scans: Vec<Option<FileScan>> = paths.iter().map(|_| None).collect();
+    while let Some(result) = workers.join_next().await {
+        for (index, scan) in result.map_err(io::Error::other)?? {
+            scans[index] = Some(scan);
+        }
+    }
+    Ok(scans.into_iter().map(|scan| scan.expect("every file is scanned")).collect())
+}
+
+async fn scan_file(path: &Path, delimiter: &str, skip_blank: bool) -> Result<FileScan, ShuffleError> {
+    debug!(path = %path.display(), "Scanning");
+    let file = File::open(path).await.map_err(|e| ShuffleError::opening_input(path, e))?;
+    let tally = Arc::new(Mutex::new((0u64, Sha256::new())));
+    let reader = HashingReader { inner: file, tally: tally.clone() };
+    let reader = compression::decoder(BufReader::new(reader), Compression::from_path(path));
+    let mut records = RecordReader::new(reader, delimiter);
+
+    let mut digest = RecordDigest::default();
+    let mut blank_records = 0;
+    while let Some(record) = records
+        .next_record()
+        .await
+        .map_err(|e| ShuffleError::reading_input(path, digest.records + blank_records, e))?
+    {
+        if skip_blank && record.trim().is_empty() {
+            blank_records += 1;
+        } else {
+            digest.add(&record);
+        }
+    }
+    drop(records);
+
+    let (bytes, sha256) = std::mem::take(&mut *tally.lock().unwrap());
+    Ok(FileScan { digest, blank_records, bytes, sha256: format!("{:x}", sha256.finalize()) })
+}
+
+/// Tallies and hashes the bytes read from the underlying reader.
+struct HashingReader<R> {
+    inner: R,
+    tally: Arc<Mutex<(u64, Sha256)>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[before..];
+            let mut tally = self.tally.lock().unwrap();
+            tally.0 += read.len() as u64;
+            tally.1.update(read);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::manifest_path;
+    use crate::shuffle::{shuffle_files, ShardMode, ShuffleConfig};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Shuffle 300 records (some repeated) and a blank line into two shards.
+    async fn shuffle(dir: &Path, compression: Compression) -> (PathBuf, ShuffleConfig, ShuffleReport) {
+        let input = dir.join("input.jsonl");
+        let content: String = (0..300).map(|i| format!("{{\"id\": {}}}\n", i % 250)).collect();
+        fs::write(&input, content + "\n").unwrap();
+        let out_dir = dir.join("out");
+        let mut config = ShuffleConfig::new(vec![input.clone()], out_dir.to_str().unwrap(), "shuffled", 64, "\n", "jsonl", Some(3))
+            .unwrap();
+        config.shard_mode = ShardMode::Count(2);
+        config.output_compression = compression;
+        let report = shuffle_files(&config).await.unwrap();
+        (input, config, report)
+    }
+
+    #[tokio::test]
+    async fn test_shuffled_output_verifies() {
+        let temp_dir = TempDir::new().unwrap();
+        let (input, config, report) = shuffle(temp_dir.path(), Compression::Gzip).await;
+
+        let verification = verify(&[input], &report.output_paths(), "\n", 2).await.unwrap();
+        assert!(verification.is_ok(), "{:?}", verification.mismatches);
+        assert_eq!(verification.inputs, verification.outputs);
+        assert_eq!(verification.inputs.records, 300);
+        assert_eq!(verification.blank_records, 1);
+
+        let from_manifest = verify_manifest(&manifest_path(&config), 2).await.unwrap();
+        assert_eq!(from_manifest, verification);
+    }
+
+    #[tokio::test]
+    async fn test_reports_altered_and_lost_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let (input, config, report) = shuffle(temp_dir.path(), Compression::None).await;
+        let shard = report.outputs[0].path.clone();
+        let original = fs::read_to_string(&shard).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+
+        // One record replaced by a copy of another
+        fs::write(&shard, format!("{}\n{}\n", lines[1], lines[1..].join("\n"))).unwrap();
+        let verification = verify(&[input], &report.output_paths(), "\n", 1).await.unwrap();
+        assert_eq!(verification.mismatches, vec![Mismatch::Records]);
+        let verification = verify_manifest(&manifest_path(&config), 1).await.unwrap();
+        assert_eq!(verification.mismatches, vec![Mismatch::FileChecksum { path: shard.clone() }, Mismatch::Records]);
+
+        // One record lost
+        fs::write(&shard, format!("{}\n", lines[1..].join("\n"))).unwrap();
+        let verification = verify_manifest(&manifest_path(&config), 1).await.unwrap();
+        assert_eq!(verification.mismatches, vec![
+            Mismatch::FileRecords { path: shard.clone(), expected: 150, found: 149 },
+            Mismatch::FileChecksum { path: shard },
+            Mismatch::RecordCount { inputs: 300, outputs: 299 },
+        ]);
+    }
+}